@@ -16,21 +16,32 @@ BASENAME=$(basename "$INPUT_FILE" .mp3)
 # Create output directory
 mkdir -p "$OUTPUT_DIR"
 
-# Convert MP3 to HLS
+# Convert MP3 to HLS, one rendition per bitrate plus a master playlist
+BITRATES=(64k 128k 256k)
+MAPS=()
+STREAM_MAP=()
+for i in "${!BITRATES[@]}"; do
+    MAPS+=(-map 0:a "-b:a:$i" "${BITRATES[$i]}")
+    STREAM_MAP+=("a:$i,name:${BITRATES[$i]}")
+done
+
 ffmpeg -i "$INPUT_FILE" \
+    "${MAPS[@]}" \
     -c:a aac \
-    -b:a 128k \
     -ac 2 \
     -ar 44100 \
     -f hls \
     -hls_time 10 \
     -hls_playlist_type vod \
-    -hls_segment_filename "$OUTPUT_DIR/${BASENAME}_%03d.ts" \
-    "$OUTPUT_DIR/${BASENAME}.m3u8"
+    -hls_segment_filename "$OUTPUT_DIR/${BASENAME}_%v_%03d.ts" \
+    -master_pl_name "${BASENAME}.m3u8" \
+    -var_stream_map "${STREAM_MAP[*]}" \
+    "$OUTPUT_DIR/${BASENAME}_%v.m3u8"
 
 echo "Conversion complete!"
-echo "Playlist: $OUTPUT_DIR/${BASENAME}.m3u8"
-echo "Segments: $OUTPUT_DIR/${BASENAME}_*.ts"
+echo "Master playlist: $OUTPUT_DIR/${BASENAME}.m3u8"
+echo "Variants: $OUTPUT_DIR/${BASENAME}_<bitrate>.m3u8"
+echo "Segments: $OUTPUT_DIR/${BASENAME}_*_*.ts"
 
 # Optional: Show the playlist content
 echo -e "\nPlaylist content:"
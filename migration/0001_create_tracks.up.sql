@@ -1,9 +1,6 @@
-CREATE DATABASE vvinamp;
-
-CREATE TABLE tracks (
+CREATE TABLE IF NOT EXISTS tracks (
 	track_id SERIAL PRIMARY KEY,
 	title VARCHAR(100) UNIQUE NOT NULL,
   duration VARCHAR(10),
   created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
 );
-
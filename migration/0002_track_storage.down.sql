@@ -0,0 +1,2 @@
+ALTER TABLE tracks DROP COLUMN IF EXISTS hls_playlist;
+ALTER TABLE tracks DROP COLUMN IF EXISTS mp3_path;
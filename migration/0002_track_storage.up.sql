@@ -0,0 +1,10 @@
+ALTER TABLE tracks ADD COLUMN IF NOT EXISTS mp3_path TEXT;
+ALTER TABLE tracks ADD COLUMN IF NOT EXISTS hls_playlist TEXT;
+
+-- Tracks ingested before ids existed are stored under their title
+UPDATE tracks SET mp3_path = title || '.mp3' WHERE mp3_path IS NULL;
+UPDATE tracks SET hls_playlist = title || '/' || title || '.m3u8' WHERE hls_playlist IS NULL;
+
+ALTER TABLE tracks
+    ALTER COLUMN mp3_path SET NOT NULL,
+    ALTER COLUMN hls_playlist SET NOT NULL;
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS jobs (
+    job_id UUID PRIMARY KEY,
+    title TEXT NOT NULL,
+    youtube_url TEXT NOT NULL,
+    clip_start INTEGER,
+    clip_end INTEGER,
+    status TEXT NOT NULL DEFAULT 'queued'
+        CHECK (status IN ('queued', 'downloading', 'converting', 'done', 'failed', 'cancelled')),
+    progress SMALLINT NOT NULL DEFAULT 0,
+    log TEXT[] NOT NULL DEFAULT '{}',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    track_id INTEGER REFERENCES tracks (track_id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS jobs_queued_idx ON jobs (created_at) WHERE status = 'queued';
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS jobs_queued_idx;
+ALTER TABLE jobs DROP COLUMN IF EXISTS run_after;
+CREATE INDEX IF NOT EXISTS jobs_queued_idx ON jobs (created_at) WHERE status = 'queued';
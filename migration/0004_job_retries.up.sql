@@ -0,0 +1,5 @@
+-- Earliest time a queued job may be claimed; pushed back between automatic retries
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS run_after TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+DROP INDEX IF EXISTS jobs_queued_idx;
+CREATE INDEX IF NOT EXISTS jobs_queued_idx ON jobs (run_after) WHERE status = 'queued';
@@ -0,0 +1,5 @@
+ALTER TABLE jobs
+    DROP COLUMN IF EXISTS convert_percent,
+    DROP COLUMN IF EXISTS download_eta,
+    DROP COLUMN IF EXISTS download_speed,
+    DROP COLUMN IF EXISTS download_percent;
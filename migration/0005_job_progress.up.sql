@@ -0,0 +1,5 @@
+ALTER TABLE jobs
+    ADD COLUMN IF NOT EXISTS download_percent REAL,
+    ADD COLUMN IF NOT EXISTS download_speed TEXT,
+    ADD COLUMN IF NOT EXISTS download_eta TEXT,
+    ADD COLUMN IF NOT EXISTS convert_percent REAL;
@@ -0,0 +1,12 @@
+DROP INDEX IF EXISTS jobs_active_video_id_idx;
+DROP INDEX IF EXISTS jobs_active_source_idx;
+ALTER TABLE jobs DROP COLUMN IF EXISTS video_id;
+
+DROP INDEX IF EXISTS tracks_source_url_idx;
+DROP INDEX IF EXISTS tracks_source_key;
+ALTER TABLE tracks ADD CONSTRAINT tracks_title_key UNIQUE (title);
+ALTER TABLE tracks
+    DROP COLUMN IF EXISTS clip_end,
+    DROP COLUMN IF EXISTS clip_start,
+    DROP COLUMN IF EXISTS video_id,
+    DROP COLUMN IF EXISTS source_url;
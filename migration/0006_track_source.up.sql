@@ -0,0 +1,18 @@
+ALTER TABLE tracks
+    ADD COLUMN IF NOT EXISTS source_url TEXT,
+    ADD COLUMN IF NOT EXISTS video_id TEXT,
+    ADD COLUMN IF NOT EXISTS clip_start INTEGER,
+    ADD COLUMN IF NOT EXISTS clip_end INTEGER;
+
+-- A track is unique per source video and clip; different videos may share a title
+ALTER TABLE tracks DROP CONSTRAINT IF EXISTS tracks_title_key;
+CREATE UNIQUE INDEX IF NOT EXISTS tracks_source_key
+    ON tracks (video_id, COALESCE(clip_start, -1), COALESCE(clip_end, -1))
+    WHERE video_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS tracks_source_url_idx ON tracks (source_url);
+
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS video_id TEXT;
+CREATE INDEX IF NOT EXISTS jobs_active_source_idx ON jobs (youtube_url)
+    WHERE status IN ('queued', 'downloading', 'converting');
+CREATE INDEX IF NOT EXISTS jobs_active_video_id_idx ON jobs (video_id)
+    WHERE status IN ('queued', 'downloading', 'converting');
@@ -0,0 +1,4 @@
+-- Tracks keep the segment container they were converted with, whatever
+-- `encoding.segment_format` says later; earlier tracks used MPEG-TS
+ALTER TABLE tracks ADD COLUMN IF NOT EXISTS segment_format TEXT NOT NULL DEFAULT 'ts'
+    CHECK (segment_format IN ('ts', 'fmp4'));
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS jobs_active_video_id_key;
+DROP INDEX IF EXISTS jobs_active_source_key;
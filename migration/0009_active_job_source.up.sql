@@ -0,0 +1,30 @@
+-- Jobs queued twice for the same source before this index existed; the
+-- earliest one stays
+UPDATE jobs SET status = 'cancelled', error = 'duplicate of an earlier job'
+WHERE job_id IN (
+    SELECT job_id FROM (
+        SELECT job_id, row_number() OVER (
+            PARTITION BY youtube_url, COALESCE(clip_start, -1), COALESCE(clip_end, -1)
+            ORDER BY created_at) AS n
+        FROM jobs WHERE status IN ('queued', 'downloading', 'converting')
+    ) active WHERE n > 1
+);
+UPDATE jobs SET status = 'cancelled', error = 'duplicate of an earlier job'
+WHERE job_id IN (
+    SELECT job_id FROM (
+        SELECT job_id, row_number() OVER (
+            PARTITION BY video_id, COALESCE(clip_start, -1), COALESCE(clip_end, -1)
+            ORDER BY created_at) AS n
+        FROM jobs
+        WHERE video_id IS NOT NULL AND status IN ('queued', 'downloading', 'converting')
+    ) active WHERE n > 1
+);
+
+-- At most one queued or running job per source and clip, so concurrent
+-- requests for the same video cannot both queue it
+CREATE UNIQUE INDEX IF NOT EXISTS jobs_active_source_key
+    ON jobs (youtube_url, COALESCE(clip_start, -1), COALESCE(clip_end, -1))
+    WHERE status IN ('queued', 'downloading', 'converting');
+CREATE UNIQUE INDEX IF NOT EXISTS jobs_active_video_id_key
+    ON jobs (video_id, COALESCE(clip_start, -1), COALESCE(clip_end, -1))
+    WHERE video_id IS NOT NULL AND status IN ('queued', 'downloading', 'converting');
@@ -1,4 +1,4 @@
-INSERT INTO tracks (title, duration) VALUES ('memori-baik', '4:47');
-INSERT INTO tracks (title, duration) VALUES ('feel-good-inc', '3:59');
+INSERT INTO tracks (title, duration, mp3_path, hls_playlist) VALUES ('memori-baik', '4:47', 'memori-baik.mp3', 'memori-baik/memori-baik.m3u8');
+INSERT INTO tracks (title, duration, mp3_path, hls_playlist) VALUES ('feel-good-inc', '3:59', 'feel-good-inc.mp3', 'feel-good-inc/feel-good-inc.m3u8');
 
-INSERT INTO tracks (title, duration) VALUES ('Bad Bunny, Jhay Cortez - Dakiti (Letra⧸Lyrics)', '3:37');
+INSERT INTO tracks (title, duration, mp3_path, hls_playlist) VALUES ('Bad Bunny, Jhay Cortez - Dakiti (Letra⧸Lyrics)', '3:37', 'Bad Bunny, Jhay Cortez - Dakiti (Letra⧸Lyrics).mp3', 'Bad Bunny, Jhay Cortez - Dakiti (Letra⧸Lyrics)/Bad Bunny, Jhay Cortez - Dakiti (Letra⧸Lyrics).m3u8');
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::progress::parse_clock;
+
+/// Latest clip boundary accepted, the largest second the database stores.
+pub const MAX_CLIP_SECS: u32 = i32::MAX as u32;
+
+/// Clip boundary in whole seconds, given as a number (`75`) or as a string
+/// of seconds, `mm:ss` or `hh:mm:ss` (`"1:15"`), up to `MAX_CLIP_SECS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(transparent)]
+pub struct ClipTime(pub u32);
+
+impl<'de> Deserialize<'de> for ClipTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Seconds(f64),
+            Text(String),
+        }
+
+        let (secs, raw) = match Raw::deserialize(deserializer)? {
+            Raw::Seconds(secs) => (Some(secs), secs.to_string()),
+            Raw::Text(text) => (parse_clock(text.trim()), text),
+        };
+        match secs {
+            Some(secs) if secs.fract() == 0.0 && (0.0..=MAX_CLIP_SECS as f64).contains(&secs) => {
+                Ok(ClipTime(secs as u32))
+            }
+            _ => Err(serde::de::Error::custom(format!(
+                "invalid clip time `{}`, expected whole seconds or mm:ss",
+                raw
+            ))),
+        }
+    }
+}
+
+/// Part of a source to keep; a missing bound means its start or end.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Clip {
+    pub start: Option<u32>,
+    pub end: Option<u32>,
+}
+
+impl Clip {
+    pub fn new(start: Option<u32>, end: Option<u32>) -> Self {
+        Self { start, end }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.start.is_none() && self.end.is_none()
+    }
+
+    /// Checks the bounds against each other and, when known, against the
+    /// length of the source in seconds.
+    pub fn validate(&self, source_secs: Option<f64>) -> Result<(), String> {
+        if let (Some(start), Some(end)) = (self.start, self.end)
+            && end <= start
+        {
+            return Err(format!(
+                "clip end ({}) must be after clip start ({})",
+                format_duration(end as f64),
+                format_duration(start as f64)
+            ));
+        }
+        let Some(source) = source_secs else {
+            return Ok(());
+        };
+        if let Some(start) = self.start.filter(|start| *start as f64 >= source) {
+            return Err(format!(
+                "clip start ({}) is not before the end of the source ({})",
+                format_duration(start as f64),
+                format_duration(source)
+            ));
+        }
+        if let Some(end) = self.end.filter(|end| *end as f64 > source) {
+            return Err(format!(
+                "clip end ({}) is past the end of the source ({})",
+                format_duration(end as f64),
+                format_duration(source)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Seconds left of a `source_secs` long source once clipped.
+    pub fn length(&self, source_secs: f64) -> f64 {
+        let end = self
+            .end
+            .map_or(source_secs, |end| source_secs.min(end as f64));
+        (end - self.start.unwrap_or(0) as f64).max(0.0)
+    }
+}
+
+/// `m:ss` or `h:mm:ss`, the way yt-dlp prints `duration_string`.
+pub fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<ClipTime, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn parses_seconds_and_clock_times() {
+        assert_eq!(parse("75").unwrap(), ClipTime(75));
+        assert_eq!(parse("\"75\"").unwrap(), ClipTime(75));
+        assert_eq!(parse("\"1:15\"").unwrap(), ClipTime(75));
+        assert_eq!(parse("\"1:00:15\"").unwrap(), ClipTime(3615));
+    }
+
+    #[test]
+    fn rejects_times_the_database_cannot_store() {
+        assert_eq!(
+            parse(&MAX_CLIP_SECS.to_string()).unwrap(),
+            ClipTime(MAX_CLIP_SECS)
+        );
+        for json in ["2147483648", "3000000000", "-1", "1.5", "\"596524:00:00\""] {
+            assert!(parse(json).is_err(), "{}", json);
+        }
+    }
+}
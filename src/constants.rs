@@ -1,22 +0,0 @@
-pub const BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request\r\n\r\n";
-pub const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
-pub const OPTIONS_CORS: &str = "HTTP/1.1 204 No Content\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: POST, GET, OPTIONS, HEAD\r\n\
-            Access-Control-Allow-Headers: Content-Type\r\n\
-            Access-Control-Max-Age: 86400\r\n\
-            \r\n";
-pub const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: POST, GET, OPTIONS, HEAD\r\n\
-            Access-Control-Allow-Headers: Content-Type\r\n\
-            Access-Control-Max-Age: 86400\r\n\
-            Content-Type: application/json\r\n\
-            \r\n";
-pub const PARTIAL_CONTENT: &str = "HTTP/1.1 206 Partial Content\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: POST, GET, OPTIONS, HEAD\r\n\
-            Access-Control-Allow-Headers: Content-Type\r\n\
-            Access-Control-Max-Age: 86400\r\n\
-            Content-Type: application/json\r\n\
-            \r\n";
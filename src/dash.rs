@@ -0,0 +1,137 @@
+use std::fmt::Write;
+
+use crate::m3u8::{ByteRange, MediaPlaylist};
+
+// Ticks per second of segment timelines.
+const TIMESCALE: u64 = 1000;
+const PROFILE: &str = "urn:mpeg:dash:profile:isoff-main:2011";
+
+/// One rendition of a track, described by its HLS media playlist.
+pub struct Representation {
+    pub id: String,
+    /// Peak bits per second.
+    pub bandwidth: u64,
+    pub codecs: Option<String>,
+    /// Media playlist whose URIs are already the ones to put in the manifest.
+    pub playlist: MediaPlaylist,
+}
+
+/// Static MPD for on-demand playback of fMP4 `representations`, listing every
+/// segment with its duration taken from the media playlists. MPEG-TS
+/// renditions have no place in it: DASH players do not play them.
+pub fn manifest(representations: &[Representation]) -> String {
+    let duration = representations
+        .iter()
+        .map(|representation| representation.playlist.duration())
+        .fold(0.0, f64::max);
+    let min_buffer = representations
+        .iter()
+        .map(|representation| representation.playlist.target_duration)
+        .max()
+        .unwrap_or_default();
+
+    let mut mpd = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        mpd,
+        "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" type=\"static\" profiles=\"{}\" \
+         mediaPresentationDuration=\"PT{:.3}S\" minBufferTime=\"PT{}S\">",
+        PROFILE, duration, min_buffer
+    );
+    mpd.push_str("  <Period id=\"0\" start=\"PT0S\">\n");
+    mpd.push_str(
+        "    <AdaptationSet id=\"0\" contentType=\"audio\" mimeType=\"audio/mp4\" segmentAlignment=\"true\">\n",
+    );
+    for representation in representations {
+        write_representation(&mut mpd, representation);
+    }
+    mpd.push_str("    </AdaptationSet>\n  </Period>\n</MPD>\n");
+    mpd
+}
+
+fn write_representation(mpd: &mut String, representation: &Representation) {
+    let _ = write!(
+        mpd,
+        "      <Representation id=\"{}\" bandwidth=\"{}\"",
+        escape(&representation.id),
+        representation.bandwidth
+    );
+    if let Some(codecs) = &representation.codecs {
+        let _ = write!(mpd, " codecs=\"{}\"", escape(codecs));
+    }
+    mpd.push_str(">\n");
+    let _ = writeln!(mpd, "        <SegmentList timescale=\"{}\">", TIMESCALE);
+
+    let segments = &representation.playlist.segments;
+    if let Some(map) = segments.first().and_then(|segment| segment.map.as_ref()) {
+        let _ = write!(
+            mpd,
+            "          <Initialization sourceURL=\"{}\"",
+            escape(&map.uri)
+        );
+        if let Some(range) = map.byte_range {
+            let _ = write!(mpd, " range=\"{}\"", media_range(range, 0));
+        }
+        mpd.push_str("/>\n");
+    }
+
+    // Durations are rounded at segment boundaries so the timeline does not drift
+    mpd.push_str("          <SegmentTimeline>\n");
+    let mut elapsed = 0.0;
+    let mut durations: Vec<u64> = Vec::new();
+    for segment in segments {
+        let start = (elapsed * TIMESCALE as f64).round() as u64;
+        elapsed += segment.duration;
+        durations.push((elapsed * TIMESCALE as f64).round() as u64 - start);
+    }
+    let mut runs = durations.chunk_by(|a, b| a == b);
+    if let Some(first) = runs.next() {
+        write_timeline_entry(mpd, Some(0), first);
+    }
+    for run in runs {
+        write_timeline_entry(mpd, None, run);
+    }
+    mpd.push_str("          </SegmentTimeline>\n");
+
+    // Ranges without an offset follow the previous range of the same URI
+    let mut next_offset = 0;
+    for segment in segments {
+        let _ = write!(
+            mpd,
+            "          <SegmentURL media=\"{}\"",
+            escape(&segment.uri)
+        );
+        if let Some(range) = segment.byte_range {
+            let _ = write!(mpd, " mediaRange=\"{}\"", media_range(range, next_offset));
+            next_offset = range.offset.unwrap_or(next_offset) + range.length;
+        }
+        mpd.push_str("/>\n");
+    }
+    mpd.push_str("        </SegmentList>\n      </Representation>\n");
+}
+
+// `<S>` for a run of segments of equal duration.
+fn write_timeline_entry(mpd: &mut String, start: Option<u64>, run: &[u64]) {
+    mpd.push_str("            <S");
+    if let Some(start) = start {
+        let _ = write!(mpd, " t=\"{}\"", start);
+    }
+    let _ = write!(mpd, " d=\"{}\"", run[0]);
+    if run.len() > 1 {
+        let _ = write!(mpd, " r=\"{}\"", run.len() - 1);
+    }
+    mpd.push_str("/>\n");
+}
+
+// Inclusive `first-last` byte range.
+fn media_range(range: ByteRange, next_offset: u64) -> String {
+    let first = range.offset.unwrap_or(next_offset);
+    format!("{}-{}", first, first + range.length.max(1) - 1)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
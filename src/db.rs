@@ -1,18 +1,96 @@
+use anyhow::{Context, bail};
 use sqlx::Pool;
+use sqlx::migrate::{Migrate, Migrator};
 use sqlx::postgres::PgPoolOptions;
 
+use crate::config::DatabaseConfig;
+
+/// Versioned migrations from `migration/`, embedded at compile time.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migration");
+
 pub struct Database {
     pub pool: Pool<sqlx::Postgres>,
 }
 
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+}
+
 impl Database {
-    pub async fn new_pool(url: &str) -> Pool<sqlx::Postgres> {
+    /// Connects and brings the schema up to date, or with `auto_migrate` off
+    /// only checks that every embedded migration has been applied.
+    pub async fn new_pool(config: &DatabaseConfig) -> anyhow::Result<Pool<sqlx::Postgres>> {
+        let pool = Self::connect(config).await?;
+        if config.auto_migrate {
+            Self::migrate_up(&pool).await?;
+        } else {
+            let pending: Vec<String> = Self::migration_status(&pool)
+                .await?
+                .into_iter()
+                .filter(|m| !m.applied)
+                .map(|m| format!("{}_{}", m.version, m.description))
+                .collect();
+            if !pending.is_empty() {
+                bail!(
+                    "database schema is out of date, pending migrations: {} (run `migrate up`)",
+                    pending.join(", ")
+                );
+            }
+        }
+        Ok(pool)
+    }
+
+    pub async fn connect(config: &DatabaseConfig) -> anyhow::Result<Pool<sqlx::Postgres>> {
         PgPoolOptions::new()
-            .max_connections(10)
-            .min_connections(5)
-            .idle_timeout(std::time::Duration::from_secs(30))
-            .connect(url)
+            .max_connections(config.max_connections)
+            .min_connections(config.min_connections)
+            .idle_timeout(std::time::Duration::from_secs(config.idle_timeout_secs))
+            .connect(&config.url)
+            .await
+            .context("Failed to create DB pool")
+    }
+
+    pub async fn migrate_up(pool: &Pool<sqlx::Postgres>) -> anyhow::Result<()> {
+        MIGRATOR
+            .run(pool)
             .await
-            .expect("Failed to create DB pool")
+            .context("failed to apply migrations")
+    }
+
+    /// Reverts the `steps` most recently applied migrations.
+    pub async fn migrate_down(pool: &Pool<sqlx::Postgres>, steps: usize) -> anyhow::Result<()> {
+        let mut applied: Vec<i64> = Self::migration_status(pool)
+            .await?
+            .into_iter()
+            .filter(|m| m.applied)
+            .map(|m| m.version)
+            .collect();
+        applied.sort_unstable_by(|a, b| b.cmp(a));
+        // Everything newer than the target version gets reverted
+        let target = applied.get(steps).copied().unwrap_or(0);
+        MIGRATOR
+            .undo(pool, target)
+            .await
+            .context("failed to revert migrations")
+    }
+
+    pub async fn migration_status(
+        pool: &Pool<sqlx::Postgres>,
+    ) -> anyhow::Result<Vec<MigrationStatus>> {
+        let mut conn = pool.acquire().await?;
+        conn.ensure_migrations_table().await?;
+        let applied = conn.list_applied_migrations().await?;
+        Ok(MIGRATOR
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration())
+            .map(|m| MigrationStatus {
+                version: m.version,
+                description: m.description.to_string(),
+                applied: applied.iter().any(|a| a.version == m.version),
+            })
+            .collect())
     }
 }
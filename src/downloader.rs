@@ -0,0 +1,352 @@
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::time::Duration;
+
+use anyhow::{Context, anyhow, bail};
+use tokio::process::Command;
+
+use crate::clip::Clip;
+use crate::model::YtSearchResult;
+use crate::process::{self, ToolOutput};
+
+/// Boxed future returned by the tool traits, which keeps them object safe.
+pub type ToolFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>;
+
+// yt-dlp errors worth another attempt later; anything else fails the job.
+const TRANSIENT_ERRORS: [&str; 9] = [
+    "HTTP Error 429",
+    "HTTP Error 5",
+    "timed out",
+    "Connection reset",
+    "Connection refused",
+    "Temporary failure in name resolution",
+    "Remote end closed connection",
+    "IncompleteRead",
+    "Unable to download webpage",
+];
+
+// Results returned by a search.
+const SEARCH_RESULTS: usize = 10;
+
+/// A failure that may go away when the job is attempted again.
+#[derive(Debug)]
+pub struct Transient(pub String);
+
+impl std::fmt::Display for Transient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Transient {}
+
+/// Runs a tool call, failing with a `Transient` error when it takes longer
+/// than `limit`. Dropping the call kills the tool.
+pub async fn timeout<T>(
+    limit: Duration,
+    what: &str,
+    work: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    match tokio::time::timeout(limit, work).await {
+        Ok(result) => result,
+        Err(_) => Err(Transient(format!("{} timed out after {}s", what, limit.as_secs())).into()),
+    }
+}
+
+/// Title and length of a source as reported by the extractor.
+#[derive(Debug, Clone)]
+pub struct TrackInfo {
+    /// `<extractor>:<id>`, the same for every URL of one video.
+    pub video_id: String,
+    pub title: String,
+    /// `duration_string` as printed by yt-dlp, e.g. `3:21`.
+    pub duration: String,
+}
+
+/// Fetches audio and metadata from a source URL.
+pub trait Downloader: Send + Sync {
+    /// Downloads the audio of `url`, cut to `clip`, as an MP3 file at `dest`.
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+        dest: &'a Path,
+        clip: Clip,
+        output: ToolOutput,
+    ) -> ToolFuture<'a, ()>;
+
+    fn info<'a>(&'a self, url: &'a str) -> ToolFuture<'a, TrackInfo>;
+
+    /// The `video_id` `info` would report, when it can be read off the URL
+    /// without asking the extractor.
+    fn video_id(&self, url: &str) -> Option<String>;
+
+    fn search<'a>(&'a self, query: &'a str) -> ToolFuture<'a, Vec<YtSearchResult>>;
+}
+
+/// Downloader backed by the `yt-dlp` executable.
+pub struct YtDlp {
+    program: String,
+}
+
+impl YtDlp {
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+        }
+    }
+
+    fn is_transient(stderr: &[String]) -> bool {
+        stderr
+            .iter()
+            .any(|line| TRANSIENT_ERRORS.iter().any(|error| line.contains(error)))
+    }
+
+    fn failure(&self, message: String, stderr: &[String]) -> anyhow::Error {
+        if Self::is_transient(stderr) {
+            return Transient(message).into();
+        }
+        anyhow!(message)
+    }
+}
+
+impl Downloader for YtDlp {
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+        dest: &'a Path,
+        clip: Clip,
+        output: ToolOutput,
+    ) -> ToolFuture<'a, ()> {
+        Box::pin(async move {
+            let mut command = Command::new(&self.program);
+            command
+                .arg("--extract-audio")
+                .arg("--audio-format")
+                .arg("mp3")
+                .arg("--force-overwrites")
+                .arg("-o")
+                .arg(dest.with_extension("%(ext)s"));
+            // Cut while extracting the audio, so only the clip is stored
+            let mut cut = Vec::new();
+            if let Some(start) = clip.start {
+                cut.push(format!("-ss {}", start));
+            }
+            if let Some(end) = clip.end {
+                cut.push(format!("-to {}", end));
+            }
+            if !cut.is_empty() {
+                command
+                    .arg("--postprocessor-args")
+                    .arg(format!("ExtractAudio:{}", cut.join(" ")));
+            }
+            command.arg(url);
+            let run = process::run(command, &output).await?;
+            if !run.status.success() {
+                let message = format!("yt-dlp failed ({})", run.status);
+                return Err(self.failure(message, &run.stderr_tail));
+            }
+            Ok(())
+        })
+    }
+
+    fn info<'a>(&'a self, url: &'a str) -> ToolFuture<'a, TrackInfo> {
+        Box::pin(async move {
+            let mut command = Command::new(&self.program);
+            command
+                .arg("--print")
+                // Use unique separator; the title goes last as it may contain anything
+                .arg("%(extractor_key)s:%(id)s|||%(duration_string)s|||%(title)s")
+                .arg(url);
+            let output = process::output(command).await?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                let message = format!("yt-dlp failed to read metadata ({})", output.status);
+                let lines: Vec<String> = stderr.lines().map(str::to_string).collect();
+                return Err(self.failure(message, &lines));
+            }
+
+            let output_text = String::from_utf8_lossy(&output.stdout);
+            let mut parts = output_text.trim().splitn(3, "|||");
+            let video_id = parts
+                .next()
+                .filter(|id| !id.trim().is_empty())
+                .ok_or_else(|| anyhow!("missing video id in yt-dlp output"))?
+                .trim()
+                .to_string();
+            let duration = parts
+                .next()
+                .ok_or_else(|| anyhow!("missing duration in yt-dlp output"))?
+                .trim()
+                .to_string();
+            let title = parts
+                .next()
+                .filter(|title| !title.trim().is_empty())
+                .ok_or_else(|| anyhow!("missing title in yt-dlp output"))?
+                .trim()
+                .to_string();
+            Ok(TrackInfo {
+                video_id,
+                title,
+                duration,
+            })
+        })
+    }
+
+    fn video_id(&self, url: &str) -> Option<String> {
+        youtube_id(url).map(|id| format!("Youtube:{}", id))
+    }
+
+    fn search<'a>(&'a self, query: &'a str) -> ToolFuture<'a, Vec<YtSearchResult>> {
+        Box::pin(async move {
+            let ytsearch_arg = format!("ytsearch{}:\"{}\"", SEARCH_RESULTS, query);
+            let mut command = Command::new(&self.program);
+            command.args([&ytsearch_arg, "--dump-json"]);
+            let output = process::output(command).await?;
+            if !output.status.success() {
+                bail!(String::from_utf8_lossy(&output.stderr).to_string());
+            }
+
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let mut results = Vec::new();
+            for line in stdout.lines() {
+                if let Ok(result) = serde_json::from_str::<YtSearchResult>(line) {
+                    results.push(result);
+                }
+            }
+            Ok(results)
+        })
+    }
+}
+
+// Id of a YouTube video in its watch, short, embed or youtu.be URL.
+fn youtube_id(url: &str) -> Option<&str> {
+    let rest = url
+        .trim()
+        .split_once("://")
+        .map_or(url.trim(), |(_, rest)| rest);
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let host = host.to_ascii_lowercase();
+    let host = ["www.", "m.", "music."]
+        .iter()
+        .find_map(|prefix| host.strip_prefix(prefix))
+        .unwrap_or(&host);
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let path = path.split('#').next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let id = match (host, segments.as_slice()) {
+        ("youtu.be", [id, ..]) => *id,
+        ("youtube.com", ["watch"]) => query
+            .split(['&', '#'])
+            .find_map(|param| param.strip_prefix("v="))?,
+        ("youtube.com", ["shorts" | "embed" | "live", id, ..]) => *id,
+        _ => return None,
+    };
+    let valid = id.len() == 11
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    valid.then_some(id)
+}
+
+// Silent MPEG-1 Layer III frame: 128 kbit/s, 44.1 kHz, mono, no padding.
+const SILENT_FRAME_HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0xC0];
+pub const SILENT_FRAME_LEN: usize = 417;
+pub const SILENT_FRAME_SECS: f64 = 1152.0 / 44100.0;
+
+/// Offline downloader for tests. Every URL yields a track titled after its
+/// last path segment, made of `duration_secs` of silent MP3 frames.
+pub struct FakeDownloader {
+    pub duration_secs: u32,
+}
+
+impl Default for FakeDownloader {
+    fn default() -> Self {
+        Self { duration_secs: 30 }
+    }
+}
+
+impl FakeDownloader {
+    fn name(url: &str) -> &str {
+        url.trim_end_matches('/').rsplit('/').next().unwrap_or(url)
+    }
+
+    fn duration_string(&self) -> String {
+        format!("{}:{:02}", self.duration_secs / 60, self.duration_secs % 60)
+    }
+
+    /// `duration_secs` of silence as a playable MP3 stream.
+    pub fn silence(duration_secs: f64) -> Vec<u8> {
+        let frames = (duration_secs / SILENT_FRAME_SECS).ceil() as usize;
+        let mut frame = vec![0; SILENT_FRAME_LEN];
+        frame[..4].copy_from_slice(&SILENT_FRAME_HEADER);
+        frame.repeat(frames)
+    }
+}
+
+impl Downloader for FakeDownloader {
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+        dest: &'a Path,
+        clip: Clip,
+        output: ToolOutput,
+    ) -> ToolFuture<'a, ()> {
+        Box::pin(async move {
+            let data = Self::silence(clip.length(self.duration_secs as f64));
+            let size = format!("{:.2}KiB", data.len() as f64 / 1024.0);
+            let _ = output.send(format!("[youtube] Extracting URL: {}", url));
+            for percent in [0, 25, 50, 75] {
+                let _ = output.send(format!(
+                    "[download] {:>5.1}% of {} at 1.00MiB/s ETA 00:00",
+                    percent as f32, size
+                ));
+            }
+            tokio::fs::write(dest, &data)
+                .await
+                .with_context(|| format!("failed to write {}", dest.display()))?;
+            let _ = output.send(format!("[download] 100% of {} in 00:00:00", size));
+            Ok(())
+        })
+    }
+
+    fn info<'a>(&'a self, url: &'a str) -> ToolFuture<'a, TrackInfo> {
+        Box::pin(async move {
+            Ok(TrackInfo {
+                video_id: format!("Fake:{}", Self::name(url)),
+                title: format!("Fake track {}", Self::name(url)),
+                duration: self.duration_string(),
+            })
+        })
+    }
+
+    fn video_id(&self, url: &str) -> Option<String> {
+        Some(format!("Fake:{}", Self::name(url)))
+    }
+
+    fn search<'a>(&'a self, query: &'a str) -> ToolFuture<'a, Vec<YtSearchResult>> {
+        Box::pin(async move {
+            let results = (1..=3)
+                .map(|n| {
+                    let url = format!("https://example.com/watch/{}-{}", query, n);
+                    YtSearchResult {
+                        title: format!("{} {}", query, n),
+                        fulltitle: format!("{} {}", query, n),
+                        view_count: Some(0),
+                        duration: Some(self.duration_secs as u64),
+                        duration_string: Some(self.duration_string()),
+                        upload_date: None,
+                        channel: Some("Fake channel".to_string()),
+                        channel_follower_count: None,
+                        like_count: None,
+                        channel_is_verified: None,
+                        thumbnails: None,
+                        thumbnail: String::new(),
+                        webpage_url: url,
+                    }
+                })
+                .collect();
+            Ok(results)
+        })
+    }
+}
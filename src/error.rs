@@ -0,0 +1,142 @@
+use serde_json::json;
+
+use crate::response::Response;
+
+/// Error returned by handlers, rendered as `{code, message, details}` JSON.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    NotFound(String),
+    MethodNotAllowed(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    /// An external tool (yt-dlp, ffmpeg) failed or could not be started.
+    Upstream {
+        message: String,
+        details: Option<String>,
+    },
+    Internal(anyhow::Error),
+}
+
+impl ApiError {
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::BadRequest(message.into())
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::NotFound(message.into())
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        ApiError::Conflict(message.into())
+    }
+
+    pub fn upstream(message: impl Into<String>, details: Option<String>) -> Self {
+        ApiError::Upstream {
+            message: message.into(),
+            details,
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::BadRequest(_) => 400,
+            ApiError::NotFound(_) => 404,
+            ApiError::MethodNotAllowed(_) => 405,
+            ApiError::Conflict(_) => 409,
+            ApiError::PayloadTooLarge(_) => 413,
+            ApiError::Upstream { .. } => 502,
+            ApiError::Internal(_) => 500,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::Upstream { .. } => "upstream_failure",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::NotFound(message)
+            | ApiError::MethodNotAllowed(message)
+            | ApiError::Conflict(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::Upstream { message, .. } => message.clone(),
+            // Internal causes stay in the server log
+            ApiError::Internal(_) => "internal server error".to_string(),
+        }
+    }
+
+    fn details(&self) -> Option<&str> {
+        match self {
+            ApiError::Upstream { details, .. } => details.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn into_response(self) -> Response {
+        match &self {
+            ApiError::Internal(e) => eprintln!("internal error: {:?}", e),
+            ApiError::Upstream { message, details } => {
+                eprintln!("upstream error: {} {:?}", message, details)
+            }
+            _ => {}
+        }
+        let body = json!({
+            "code": self.code(),
+            "message": self.message(),
+            "details": self.details(),
+        });
+        Response::json(self.status(), &body).unwrap_or_else(|_| Response::internal_error())
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Internal(e) => write!(f, "{}: {}", self.code(), e),
+            _ => write!(f, "{}: {}", self.code(), self.message()),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => ApiError::not_found("record not found"),
+            sqlx::Error::Database(db) if db.is_unique_violation() => {
+                ApiError::conflict(db.message().to_string())
+            }
+            _ => ApiError::Internal(e.into()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<sqlx::Error>() {
+            Ok(db) => db.into(),
+            Err(e) => ApiError::Internal(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
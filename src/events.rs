@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+use crate::model::TaskStatus;
+use crate::repo::Repository;
+
+pub const CONTENT_TYPE_EVENT_STREAM: &str = "text/event-stream";
+
+// Events kept for clients resuming with `Last-Event-ID`.
+const HISTORY: usize = 1024;
+// Comment line sent to idle subscribers so proxies keep the connection open
+// and a vanished client is noticed.
+const HEARTBEAT: Duration = Duration::from_secs(15);
+// Milliseconds browsers wait before reconnecting.
+const RECONNECT_MS: u64 = 3000;
+
+/// One server-sent event about a task.
+#[derive(Debug, Clone)]
+pub struct TaskEvent {
+    pub id: u64,
+    pub task_id: Uuid,
+    /// `snapshot`, `update`, `log` or `deleted`.
+    pub kind: &'static str,
+    /// JSON payload.
+    pub data: String,
+}
+
+impl TaskEvent {
+    fn encode(&self) -> Vec<u8> {
+        format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id, self.kind, self.data
+        )
+        .into_bytes()
+    }
+}
+
+struct History {
+    next_id: u64,
+    events: VecDeque<TaskEvent>,
+}
+
+/// Broadcasts task changes from workers and handlers to `text/event-stream`
+/// subscribers, keeping recent events so a reconnecting client can resume.
+#[derive(Clone)]
+pub struct TaskEvents {
+    history: Arc<Mutex<History>>,
+    sender: broadcast::Sender<TaskEvent>,
+}
+
+impl Default for TaskEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(256);
+        Self {
+            history: Arc::new(Mutex::new(History {
+                next_id: 1,
+                events: VecDeque::with_capacity(HISTORY),
+            })),
+            sender,
+        }
+    }
+
+    /// Publishes the current status and progress of a task.
+    pub async fn task_changed(&self, task_id: Uuid, pool: &Pool<Postgres>) {
+        match Repository::fetch_task(task_id, pool).await {
+            Ok(Some(task)) => self.publish(task_id, "update", update_data(&task)),
+            Ok(None) => {}
+            Err(e) => eprintln!("failed to load task {} for its event: {:?}", task_id, e),
+        }
+    }
+
+    /// Publishes a line appended to the task log.
+    pub fn log(&self, task_id: Uuid, line: &str) {
+        let data = json!({ "task_id": task_id, "line": line }).to_string();
+        self.publish(task_id, "log", data);
+    }
+
+    pub fn deleted(&self, task_id: Uuid) {
+        let data = json!({ "task_id": task_id }).to_string();
+        self.publish(task_id, "deleted", data);
+    }
+
+    fn publish(&self, task_id: Uuid, kind: &'static str, data: String) {
+        // Ids are handed out under the lock so history and broadcast agree on order
+        let mut history = self.history.lock().unwrap();
+        let event = TaskEvent {
+            id: history.next_id,
+            task_id,
+            kind,
+            data,
+        };
+        history.next_id += 1;
+        if history.events.len() == HISTORY {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+        let _ = self.sender.send(event);
+    }
+
+    /// Event stream for one task, or for all tasks when `task_id` is `None`.
+    ///
+    /// A client resuming from `last_event_id` gets the events it missed when
+    /// they are still in the history; otherwise, and on a fresh connection, it
+    /// starts with a `snapshot` of each task.
+    pub fn subscribe(
+        &self,
+        task_id: Option<Uuid>,
+        last_event_id: Option<u64>,
+        pool: Arc<Pool<Postgres>>,
+    ) -> mpsc::Receiver<Vec<u8>> {
+        let (receiver, replay) = {
+            let history = self.history.lock().unwrap();
+            let receiver = self.sender.subscribe();
+            let newest = history.next_id - 1;
+            let oldest = history.events.front().map_or(history.next_id, |e| e.id);
+            let replay = last_event_id
+                .filter(|last| *last + 1 >= oldest && *last <= newest)
+                .map(|last| {
+                    history
+                        .events
+                        .iter()
+                        .filter(|e| e.id > last)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                });
+            (receiver, replay)
+        };
+
+        let (tx, rx) = mpsc::channel(16);
+        let events = self.clone();
+        tokio::spawn(async move {
+            events.stream(task_id, receiver, replay, &pool, tx).await;
+        });
+        rx
+    }
+
+    async fn stream(
+        &self,
+        task_id: Option<Uuid>,
+        mut receiver: broadcast::Receiver<TaskEvent>,
+        replay: Option<Vec<TaskEvent>>,
+        pool: &Pool<Postgres>,
+        tx: mpsc::Sender<Vec<u8>>,
+    ) {
+        let wanted = |event: &TaskEvent| task_id.is_none_or(|id| id == event.task_id);
+        if tx
+            .send(format!("retry: {}\n\n", RECONNECT_MS).into_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let start = match replay {
+            Some(events) => {
+                let mut sent = true;
+                for event in events.iter().filter(|e| wanted(e)) {
+                    sent = tx.send(event.encode()).await.is_ok();
+                    if !sent {
+                        break;
+                    }
+                }
+                sent
+            }
+            None => self.send_snapshots(task_id, pool, &tx).await,
+        };
+        if !start {
+            return;
+        }
+
+        let mut heartbeat = tokio::time::interval(HEARTBEAT);
+        heartbeat.tick().await;
+        loop {
+            let sent = tokio::select! {
+                event = receiver.recv() => match event {
+                    Ok(event) if wanted(&event) => tx.send(event.encode()).await.is_ok(),
+                    Ok(_) => true,
+                    // Too slow to keep up: start over from the current state
+                    Err(RecvError::Lagged(_)) => self.send_snapshots(task_id, pool, &tx).await,
+                    Err(RecvError::Closed) => false,
+                },
+                _ = heartbeat.tick() => tx.send(b": heartbeat\n\n".to_vec()).await.is_ok(),
+            };
+            if !sent {
+                return;
+            }
+        }
+    }
+
+    // Sends the full state of the subscribed tasks, tagged with the id of the
+    // newest event so a later resume continues from here.
+    async fn send_snapshots(
+        &self,
+        task_id: Option<Uuid>,
+        pool: &Pool<Postgres>,
+        tx: &mpsc::Sender<Vec<u8>>,
+    ) -> bool {
+        let id = self.history.lock().unwrap().next_id - 1;
+        let tasks = match task_id {
+            Some(task_id) => Repository::fetch_task(task_id, pool)
+                .await
+                .map(|task| task.into_iter().collect()),
+            None => Repository::fetch_all_tasks(pool).await,
+        };
+        let tasks = match tasks {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                eprintln!("failed to load tasks for event stream: {:?}", e);
+                return false;
+            }
+        };
+        for task in tasks {
+            let event = TaskEvent {
+                id,
+                task_id: task.task_id,
+                kind: "snapshot",
+                data: serde_json::to_string(&task).unwrap_or_default(),
+            };
+            if tx.send(event.encode()).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// A task without its log; log lines travel as their own events.
+#[derive(Serialize)]
+struct TaskUpdate<'a> {
+    task_id: Uuid,
+    title: &'a str,
+    status: &'a str,
+    progress: u8,
+    track_id: Option<i32>,
+    error: Option<&'a str>,
+    download_percent: Option<f32>,
+    download_speed: Option<&'a str>,
+    download_eta: Option<&'a str>,
+    convert_percent: Option<f32>,
+}
+
+fn update_data(task: &TaskStatus) -> String {
+    let update = TaskUpdate {
+        task_id: task.task_id,
+        title: &task.title,
+        status: &task.status,
+        progress: task.progress,
+        track_id: task.track_id,
+        error: task.error.as_deref(),
+        download_percent: task.download_percent,
+        download_speed: task.download_speed.as_deref(),
+        download_eta: task.download_eta.as_deref(),
+        convert_percent: task.convert_percent,
+    };
+    serde_json::to_string(&update).unwrap_or_default()
+}
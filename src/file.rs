@@ -1,267 +1,205 @@
-use crate::constants::NOT_FOUND;
-use crate::constants::OK_RESPONSE;
-use crate::model::AddStream;
-use crate::model::Track;
+use crate::error::ApiError;
+use crate::events::CONTENT_TYPE_EVENT_STREAM;
+use crate::http::{json_body, query_param};
+use crate::model::{AddStream, JobStatus};
 use crate::repo::Repository;
-use chrono::Utc;
-use once_cell::sync::Lazy;
+use crate::response::Response;
+use crate::router::Params;
+use crate::server::AppState;
+use crate::worker::Worker;
 use request_http_parser::parser::Request;
 use serde_json::json;
-use sqlx::Pool;
-use sqlx::Postgres;
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::Arc;
-use tokio::fs::{self};
-use tokio::io::AsyncWriteExt;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use crate::model::TaskStatus;
-
-static TASKS: Lazy<RwLock<HashMap<String, TaskStatus>>> = Lazy::new(|| RwLock::new(HashMap::new()));
-
 pub struct File {}
 
+/// Outcome of a download request.
+pub enum Download {
+    Queued(Uuid),
+    /// The same source is already queued or downloading under this task.
+    InFlight(Uuid),
+    /// The same source is already stored as this track.
+    Stored(i32),
+}
+
 impl File {
-    pub async fn download_task(
-        mut socket: tokio::net::TcpStream,
-        request: Request,
-        pool: Arc<Pool<Postgres>>,
-    ) -> std::io::Result<()> {
-        let body_str = match request.body {
-            Some(body) => body,
-            None => {
-                let _ = socket
-                    .write_all(format!("{}{}", NOT_FOUND, "404 Not Found").as_bytes())
-                    .await;
-                return Ok(());
-            }
+    pub async fn download_task(request: Request, state: AppState) -> Result<Response, ApiError> {
+        match Self::enqueue(&request, &state).await? {
+            Download::Queued(task_id) => Ok(Response::json(200, &json!({ "task_id": task_id }))?),
+            Download::InFlight(task_id) => Ok(Response::json(
+                200,
+                &json!({ "task_id": task_id, "existing": true }),
+            )?),
+            Download::Stored(track_id) => Ok(Response::json(
+                200,
+                &json!({ "track_id": track_id, "existing": true }),
+            )?),
+        }
+    }
+
+    /// Queues the download described by an `AddStream` request body, unless
+    /// the same source and clip is already stored or being downloaded.
+    pub async fn enqueue(request: &Request, state: &AppState) -> Result<Download, ApiError> {
+        let body: AddStream = json_body(request)?;
+        let clip = body.clip();
+        clip.validate(None).map_err(ApiError::bad_request)?;
+
+        let url = body.youtube_url.trim();
+        let video_id = state.downloader.video_id(url);
+        let pool = &state.pool;
+        if let Some(track) =
+            Repository::fetch_track_by_source(url, video_id.as_deref(), clip, pool).await?
+        {
+            return Ok(Download::Stored(track.track_id.unwrap_or_default()));
+        }
+        if let Some(task_id) =
+            Repository::fetch_active_job_by_source(url, video_id.as_deref(), clip, pool).await?
+        {
+            return Ok(Download::InFlight(task_id));
+        }
+
+        let body = AddStream {
+            youtube_url: url.to_string(),
+            ..body
         };
-        println!(" body str{}", body_str);
-        let body: AddStream = match serde_json::from_str::<AddStream>(&body_str) {
-            Ok(body) => body,
-            Err(_) => {
-                let _ = socket
-                    .write_all(format!("{}{}", NOT_FOUND, "404 Not Found").as_bytes())
-                    .await;
-                return Ok(());
+        let Some(task_id) = state
+            .queue
+            .enqueue(&body, video_id.as_deref(), pool)
+            .await?
+        else {
+            // A concurrent request queued it first; that job may even have
+            // finished since
+            if let Some(task_id) =
+                Repository::fetch_active_job_by_source(url, video_id.as_deref(), clip, pool).await?
+            {
+                return Ok(Download::InFlight(task_id));
             }
+            if let Some(track) =
+                Repository::fetch_track_by_source(url, video_id.as_deref(), clip, pool).await?
+            {
+                return Ok(Download::Stored(track.track_id.unwrap_or_default()));
+            }
+            return Err(ApiError::conflict(
+                "the same download was queued concurrently, try again",
+            ));
         };
-        println!(" body {:?}", body);
-
-        let task_id = Self::spawn_download_task(body, pool).await;
-        let payload = json!({
-            "task_id": task_id,
-        })
-        .to_string();
-        socket
-            .write_all(format!("{}{}", OK_RESPONSE, payload).as_bytes())
-            .await
-            .expect("Failed to write");
-        Ok(())
+        state.events.task_changed(task_id, pool).await;
+        Ok(Download::Queued(task_id))
     }
 
-    pub async fn get_task_status(
-        mut socket: tokio::net::TcpStream,
-        request: Request,
-    ) -> std::io::Result<()> {
-        let task_id = match &request.params {
-            Some(params) => match params.get("task_id") {
-                Some(song) => song,
-                None => {
-                    let _ = socket
-                        .write_all(format!("{}{}", NOT_FOUND, "404 Not Found").as_bytes())
-                        .await;
-                    return Ok(());
-                }
-            },
-            None => {
-                let tasks = TASKS.read().await;
-                let all_tasks: Vec<TaskStatus> = tasks.values().cloned().collect();
-                let json = serde_json::to_string(&all_tasks).expect("error serializing all tasks");
-                socket
-                    .write_all(format!("{}{}", OK_RESPONSE, json).as_bytes())
-                    .await
-                    .expect("Failed to write");
-                return Ok(());
-            }
-        };
-        let tasks = TASKS.read().await;
-        if let Some(status) = tasks.get(task_id) {
-            let json: String = serde_json::to_string::<TaskStatus>(&status).expect("error serde");
-            socket
-                .write_all(format!("{}{}", OK_RESPONSE, json).as_bytes())
-                .await
-                .expect("Failed to write");
-        } else {
-            socket
-                .write_all(format!("{}{}", NOT_FOUND, "404 Not Found").as_bytes())
-                .await
-                .expect("Failed to write");
+    pub async fn get_task_status(request: Request, state: AppState) -> Result<Response, ApiError> {
+        if request.params.is_none() {
+            return Self::list_tasks(state).await;
         }
-
-        Ok(())
+        let task_id = query_param(&request, "task_id")?;
+        Self::task_response(&task_id, &state).await
     }
-    pub async fn spawn_download_task(body: AddStream, pool: Arc<Pool<Postgres>>) -> String {
-        print!("in");
-
-        let task_id = Uuid::new_v4().to_string();
-        let task_id_for_spawn = task_id.clone();
-
-        let status = TaskStatus {
-            task_id: task_id.clone(),
-            title: body.title,
-            status: "downloading".to_string(),
-            progress: 0,
-            log: vec![],
-        };
-        TASKS.write().await.insert(task_id.clone(), status.clone());
-
-        tokio::spawn(async move {
-            // Step 1: yt-dlp
-            let mut yt_cmd = tokio::process::Command::new("yt-dlp")
-                .arg("--extract-audio")
-                .arg("--audio-format")
-                .arg("mp3")
-                .arg("-o")
-                .arg("./mp3/%(title)s.%(ext)s")
-                .arg(&body.youtube_url)
-                .stdout(std::process::Stdio::piped())
-                .stderr(std::process::Stdio::piped())
-                .spawn()
-                .expect("Failed to start yt-dlp");
-
-            if let Some(stderr) = yt_cmd.stderr.take() {
-                let reader = BufReader::new(stderr).lines();
 
-                tokio::pin!(reader);
+    pub async fn list_tasks(state: AppState) -> Result<Response, ApiError> {
+        let all_tasks = Repository::fetch_all_tasks(&state.pool).await?;
+        Ok(Response::json(200, &all_tasks)?)
+    }
 
-                while let Ok(Some(line)) = reader.next_line().await {
-                    println!("line {}", line);
-                    if line.contains("[download]") {
-                        // Optional: parse %
-                    }
+    pub async fn get_task(params: Params, state: AppState) -> Result<Response, ApiError> {
+        match params.get("id") {
+            Some(task_id) => Self::task_response(task_id, &state).await,
+            None => Err(ApiError::bad_request("missing task id")),
+        }
+    }
 
-                    let mut tasks = TASKS.write().await;
-                    if let Some(t) = tasks.get_mut(&task_id) {
-                        t.log.push(line.clone());
-                    }
+    /// Streams changes of one task (or of all tasks without `task_id`) as
+    /// server-sent events, resuming after the client's `Last-Event-ID`.
+    pub async fn task_events(
+        request: Request,
+        task_id: Option<String>,
+        state: AppState,
+    ) -> Result<Response, ApiError> {
+        let task_id = match task_id {
+            Some(task_id) => {
+                let job_id = Self::parse_task_id(&task_id)?;
+                if Repository::fetch_task(job_id, &state.pool).await?.is_none() {
+                    return Err(ApiError::not_found(format!("task `{}` not found", task_id)));
                 }
+                Some(job_id)
             }
+            None => None,
+        };
+        let last_event_id = match request.headers.get("last-event-id") {
+            Some(id) => Some(id.trim().parse::<u64>().map_err(|_| {
+                ApiError::bad_request(format!("invalid Last-Event-ID `{}`", id.trim()))
+            })?),
+            None => None,
+        };
 
-            let yt_status = yt_cmd.wait().await;
-            print!("yt_status: {:?}", yt_status);
+        let chunks = state
+            .events
+            .subscribe(task_id, last_event_id, state.pool.clone());
+        Ok(Response::ok()
+            .header("Cache-Control", "no-cache")
+            .stream(CONTENT_TYPE_EVENT_STREAM, chunks))
+    }
 
-            if yt_status.is_err() || !yt_status.unwrap().success() {
-                let mut tasks = TASKS.write().await;
-                if let Some(t) = tasks.get_mut(&task_id) {
-                    t.status = "failed".into();
-                    t.log.push("yt-dlp failed".into());
-                }
-                return;
+    /// Cancels a queued or running task, killing its tools and removing partial
+    /// files. A task that already finished is deleted instead; the files of a
+    /// `done` task belong to its track and are kept.
+    pub async fn delete_task(params: Params, state: AppState) -> Result<Response, ApiError> {
+        let task_id = params
+            .get("id")
+            .ok_or_else(|| ApiError::bad_request("missing task id"))?;
+        let job_id = Self::parse_task_id(task_id)?;
+
+        if Repository::cancel_job(job_id, &state.pool).await? {
+            // A worker running the job cleans up once its tools are gone
+            if !state.queue.cancel(job_id) {
+                Worker::remove_files(job_id, &state.config).await;
             }
+            state.events.task_changed(job_id, &state.pool).await;
+            return Self::task_response(task_id, &state).await;
+        }
 
-            // get information from youtube
-            let output = tokio::process::Command::new("yt-dlp")
-                .arg("--print")
-                .arg("%(title)s|||%(duration_string)s") // Use unique separator
-                .arg(&body.youtube_url)
-                .output()
-                .await
-                .expect("Failed to execute yt-dlp");
-
-            let output_text = String::from_utf8_lossy(&output.stdout);
-            let mut parts = output_text.trim().splitn(2, "|||");
-
-            let title = parts
-                .next()
-                .ok_or("Missing title in output")
-                .expect("")
-                .trim()
-                .to_string();
-
-            let duration = parts
-                .next()
-                .ok_or("Missing duration in output")
-                .expect("")
-                .trim()
-                .to_string();
-
-            // Step 2: ffmpeg HLS
-            let dir = format!("./hls/{}", &title);
-            let dir_path = Path::new(&dir);
-            fs::create_dir(dir_path)
-                .await
-                .expect("failed create hls title folder");
-            let input_path = format!("./mp3/{}.mp3", title);
-            let output_m3u8 = format!("{}/{}.m3u8", &dir, title);
-            let output_ts_pattern = format!("{}/{}_%03d.ts", &dir, title);
-
-            let mut ffmpeg_cmd = tokio::process::Command::new("ffmpeg")
-                .args([
-                    "-i",
-                    &input_path,
-                    "-c:a",
-                    "aac",
-                    "-b:a",
-                    "128k",
-                    "-ac",
-                    "2",
-                    "-ar",
-                    "44100",
-                    "-f",
-                    "hls",
-                    "-hls_time",
-                    "10",
-                    "-hls_playlist_type",
-                    "vod",
-                    "-hls_segment_filename",
-                    &output_ts_pattern,
-                    &output_m3u8,
-                ])
-                .stderr(std::process::Stdio::piped())
-                .spawn()
-                .expect("Failed to start ffmpeg");
-
-            if let Some(stderr) = ffmpeg_cmd.stderr.take() {
-                let reader = BufReader::new(stderr).lines();
-                tokio::pin!(reader);
-                while let Ok(Some(line)) = reader.next_line().await {
-                    // Log progress
-                    let mut tasks = TASKS.write().await;
-                    if let Some(t) = tasks.get_mut(&task_id) {
-                        t.log.push(line.clone());
-                    }
+        match Repository::delete_job(job_id, &state.pool).await? {
+            Some(status) => {
+                if status != JobStatus::Done.as_str() {
+                    Worker::remove_files(job_id, &state.config).await;
                 }
+                state.events.deleted(job_id);
+                Ok(Response::new(204))
             }
+            None => Err(ApiError::not_found(format!("task `{}` not found", task_id))),
+        }
+    }
 
-            let ffmpeg_status = ffmpeg_cmd.wait().await;
-            let mut tasks = TASKS.write().await;
-            if let Some(t) = tasks.get_mut(&task_id) {
-                if ffmpeg_status.is_ok() && ffmpeg_status.unwrap().success() {
-                    // TODO SAVE DB
-                    println!("save to db {} with duration {}", title, duration);
-                    let new_track = Track {
-                        title,
-                        duration,
-                        track_id: None,
-                        created_at: Utc::now(),
-                    };
-                    let track_id = Repository::insert_track(&new_track, &pool)
-                        .await
-                        .expect("error insert db");
-                    println!("inserted {}", track_id);
-                    t.status = "done".into();
-                    t.progress = 100;
-                } else {
-                    t.status = "failed".into();
-                    t.log.push("ffmpeg failed".into());
-                }
-            }
-        });
+    /// Runs a failed or cancelled task again with its original request.
+    pub async fn retry_task(params: Params, state: AppState) -> Result<Response, ApiError> {
+        let task_id = params
+            .get("id")
+            .ok_or_else(|| ApiError::bad_request("missing task id"))?;
+        let job_id = Self::parse_task_id(task_id)?;
+
+        if !Repository::retry_job(job_id, &state.pool).await? {
+            return match Repository::fetch_task(job_id, &state.pool).await? {
+                Some(task) => Err(ApiError::conflict(format!(
+                    "task `{}` is {} and cannot be retried",
+                    task_id, task.status
+                ))),
+                None => Err(ApiError::not_found(format!("task `{}` not found", task_id))),
+            };
+        }
+        state.queue.wake();
+        state.events.task_changed(job_id, &state.pool).await;
+        Self::task_response(task_id, &state).await
+    }
+
+    async fn task_response(task_id: &str, state: &AppState) -> Result<Response, ApiError> {
+        let job_id = Self::parse_task_id(task_id)?;
+        match Repository::fetch_task(job_id, &state.pool).await? {
+            Some(status) => Ok(Response::json(200, &status)?),
+            None => Err(ApiError::not_found(format!("task `{}` not found", task_id))),
+        }
+    }
 
-        task_id_for_spawn
+    fn parse_task_id(task_id: &str) -> Result<Uuid, ApiError> {
+        Uuid::parse_str(task_id)
+            .map_err(|_| ApiError::bad_request(format!("invalid task id `{}`", task_id)))
     }
 }
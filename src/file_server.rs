@@ -0,0 +1,253 @@
+use std::fs::Metadata;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use chrono::{DateTime, Utc};
+use request_http_parser::parser::Request;
+use tokio::fs::File;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::http::{http_date, parse_http_date};
+use crate::range::{ByteRange, RangeRequest, if_range_matches};
+use crate::response::{FilePart, Response};
+
+/// Serves files from disk for every media endpoint, answering `Range` and
+/// `If-Range` requests with `206`, `multipart/byteranges` or `416`, and
+/// conditional requests with `304`.
+pub struct FileServer {}
+
+/// Strong validators of a file on disk, derived from its size and mtime.
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    pub fn new(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let mtime = modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Self {
+            etag: format!(
+                "\"{:x}-{:x}{:08x}\"",
+                metadata.len(),
+                mtime.as_secs(),
+                mtime.subsec_nanos()
+            ),
+            last_modified: modified.map(DateTime::<Utc>::from),
+        }
+    }
+
+    /// Validators of a resource generated from the file, e.g. a playlist
+    /// describing it. `inputs` is everything else the body depends on, so
+    /// the resource and the file never share an entity tag.
+    pub fn derived(&self, inputs: &str) -> Self {
+        Self {
+            etag: format!(
+                "{}-{:016x}\"",
+                self.etag.trim_end_matches('"'),
+                fnv1a(inputs.as_bytes())
+            ),
+            last_modified: self.last_modified,
+        }
+    }
+
+    /// Whether a `GET`/`HEAD` can be answered with `304`. As in RFC 7232
+    /// section 6, `If-Modified-Since` only counts without `If-None-Match`.
+    pub fn not_modified(&self, request: &Request) -> bool {
+        if let Some(tags) = request.headers.get("if-none-match") {
+            // Weak comparison: `W/"x"` matches `"x"`
+            return tags
+                .split(',')
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == self.etag);
+        }
+        let since = request
+            .headers
+            .get("if-modified-since")
+            .and_then(|since| parse_http_date(since));
+        match (since, self.last_modified) {
+            (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    pub fn apply(&self, response: Response) -> Response {
+        let response = response.header("ETag", self.etag.as_str());
+        match self.last_modified {
+            Some(modified) => response.header("Last-Modified", http_date(modified)),
+            None => response,
+        }
+    }
+
+    pub fn not_modified_response(&self) -> Response {
+        self.apply(Response::new(304))
+    }
+}
+
+impl FileServer {
+    /// `path` must already be resolved inside a media root.
+    pub async fn serve(
+        request: &Request,
+        path: &Path,
+        content_type: &str,
+    ) -> Result<Response, ApiError> {
+        // Anything but a missing file is logged as an internal error
+        let file = match File::open(path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ApiError::not_found("file not found"));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let metadata = file.metadata().await?;
+        let validators = Validators::new(&metadata);
+        Ok(Self::serve_slice(
+            request,
+            file,
+            &validators,
+            content_type,
+            &[],
+            0,
+            metadata.len(),
+        ))
+    }
+
+    /// Serves `prefix` followed by `len` bytes of `file` from `offset` as a
+    /// resource of its own, identified by `validators`. Ranges are relative
+    /// to that resource.
+    pub fn serve_slice(
+        request: &Request,
+        file: File,
+        validators: &Validators,
+        content_type: &str,
+        prefix: &[u8],
+        offset: u64,
+        len: u64,
+    ) -> Response {
+        if validators.not_modified(request) {
+            return validators.not_modified_response();
+        }
+
+        let size = prefix.len() as u64 + len;
+        let range = match request.headers.get("range") {
+            Some(range) if Self::if_range_holds(request, validators) => {
+                ByteRange::parse(range, size)
+            }
+            _ => RangeRequest::Full,
+        };
+
+        let response = match range {
+            RangeRequest::Full if prefix.is_empty() => {
+                Response::ok().file(content_type, file, offset, len)
+            }
+            RangeRequest::Full => {
+                let whole = ByteRange {
+                    start: 0,
+                    end: size - 1,
+                };
+                let part = Self::part(prefix, offset, whole, Vec::new());
+                Response::ok().file_parts(content_type, file, vec![part], Vec::new())
+            }
+            RangeRequest::Unsatisfiable => {
+                Response::new(416).header("Content-Range", format!("bytes */{}", size))
+            }
+            RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+                let range = ranges[0];
+                let part = Self::part(prefix, offset, range, Vec::new());
+                Response::new(206)
+                    .header("Content-Range", range.content_range(size))
+                    .file_parts(content_type, file, vec![part], Vec::new())
+            }
+            RangeRequest::Partial(ranges) => {
+                Self::multipart(file, &ranges, prefix, offset, size, content_type)
+            }
+        };
+        validators.apply(response.header("Accept-Ranges", "bytes"))
+    }
+
+    // `range` of a resource made of `prefix` and the file from `offset`, sent
+    // after `header`.
+    fn part(prefix: &[u8], offset: u64, range: ByteRange, mut header: Vec<u8>) -> FilePart {
+        let prefix_len = prefix.len() as u64;
+        if range.start < prefix_len {
+            let end = (range.end + 1).min(prefix_len);
+            header.extend_from_slice(&prefix[range.start as usize..end as usize]);
+        }
+        let file_start = range.start.max(prefix_len);
+        FilePart {
+            header,
+            offset: offset + file_start - prefix_len,
+            len: (range.end + 1).saturating_sub(file_start),
+        }
+    }
+
+    // A stale `If-Range` means the client's partial copy is outdated, so the
+    // whole file is sent instead.
+    fn if_range_holds(request: &Request, validators: &Validators) -> bool {
+        match request.headers.get("if-range") {
+            Some(if_range) => {
+                if_range_matches(if_range, Some(&validators.etag), validators.last_modified)
+            }
+            None => true,
+        }
+    }
+
+    fn multipart(
+        file: File,
+        ranges: &[ByteRange],
+        prefix: &[u8],
+        offset: u64,
+        size: u64,
+        content_type: &str,
+    ) -> Response {
+        let boundary = Uuid::new_v4().simple().to_string();
+        let parts = ranges
+            .iter()
+            .map(|range| {
+                let header = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    boundary,
+                    content_type,
+                    range.content_range(size)
+                );
+                Self::part(prefix, offset, *range, header.into_bytes())
+            })
+            .collect();
+        let trailer = format!("\r\n--{}--\r\n", boundary).into_bytes();
+        Response::new(206).file_parts(
+            &format!("multipart/byteranges; boundary={}", boundary),
+            file,
+            parts,
+            trailer,
+        )
+    }
+}
+
+// 64-bit FNV-1a. Entity tags outlive the process, so unlike `DefaultHasher`
+// the hash must not change between Rust releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derived_entity_tags_are_stable() {
+        let file = Validators {
+            etag: "\"1a2b-5f3e00000000\"".to_string(),
+            last_modified: None,
+        };
+        let playlist = file.derived("mp3.m3u8 track=1 hls_time=10 packed=true");
+        assert_eq!(playlist.etag, "\"1a2b-5f3e00000000-14f36c573be68685\"");
+        let other = file.derived("mp3.m3u8 track=1 hls_time=6 packed=true");
+        assert_ne!(other.etag, playlist.etag);
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+    }
+}
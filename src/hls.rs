@@ -1,385 +1,509 @@
+use crate::config::SegmentFormat;
+use crate::dash::{self, Representation};
+use crate::error::ApiError;
+use crate::file_server::{FileServer, Validators};
+use crate::http::query_param;
+use crate::m3u8::{ByteRange, MediaPlaylist, Playlist, PlaylistType, Segment};
+use crate::model::Track;
+use crate::mp3::{FrameRange, Mp3Stream};
+use crate::response::{
+    CONTENT_TYPE_M3U8, CONTENT_TYPE_MP3, CONTENT_TYPE_MP4, CONTENT_TYPE_MPD, CONTENT_TYPE_TS,
+    Response,
+};
+use crate::router::Params;
+use crate::safe_path;
+use crate::server::AppState;
+use crate::track::TrackService;
+use anyhow::{Context, anyhow};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
 use request_http_parser::parser::Request;
-use std::io::SeekFrom;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::AsyncReadExt;
+
+// Everything except RFC 3986 unreserved characters
+const SEGMENT_NAME: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// Most MP3 files whose segment tables are kept in memory.
+const MP3_SEGMENTS_CACHED: usize = 256;
+
+/// Segment tables of stored MP3 files, so a file is only scanned again after
+/// it changed.
+#[derive(Clone, Default)]
+pub struct Mp3Segments {
+    tables: Arc<Mutex<HashMap<PathBuf, Mp3SegmentTable>>>,
+}
+
+struct Mp3SegmentTable {
+    etag: String,
+    target_secs: f64,
+    segments: Arc<Vec<FrameRange>>,
+}
+
+impl Mp3Segments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Segments of about `target_secs` of the MP3 at `path`, scanned on the
+    /// blocking pool unless the table for these validators is cached.
+    pub async fn get(
+        &self,
+        path: &Path,
+        validators: &Validators,
+        target_secs: f64,
+    ) -> anyhow::Result<Arc<Vec<FrameRange>>> {
+        if let Some(table) = self.tables.lock().unwrap().get(path)
+            && table.etag == validators.etag
+            && table.target_secs == target_secs
+        {
+            return Ok(Arc::clone(&table.segments));
+        }
 
-// TODO
-// - if the title have "&" in there, the query got bug since we split the value with char "&",
-// by now we avoid title with "&" char. maybe "=" made bug too(?)
+        let file = path.to_path_buf();
+        let segments = tokio::task::spawn_blocking(move || {
+            let stream = std::fs::File::open(&file)
+                .and_then(Mp3Stream::read)
+                .with_context(|| format!("failed to read {}", file.display()))?
+                .ok_or_else(|| anyhow!("no MPEG audio frames in {}", file.display()))?;
+            anyhow::Ok(stream.segments(target_secs))
+        })
+        .await
+        .context("MP3 scan panicked")??;
+
+        let segments = Arc::new(segments);
+        let mut tables = self.tables.lock().unwrap();
+        if tables.len() >= MP3_SEGMENTS_CACHED
+            && !tables.contains_key(path)
+            && let Some(evicted) = tables.keys().next().cloned()
+        {
+            tables.remove(&evicted);
+        }
+        tables.insert(
+            path.to_path_buf(),
+            Mp3SegmentTable {
+                etag: validators.etag.clone(),
+                target_secs,
+                segments: Arc::clone(&segments),
+            },
+        );
+        Ok(segments)
+    }
+}
 
 pub struct HlsService {}
 
 impl HlsService {
+    /// HLS straight from the stored MP3, without pre-generated segments. The
+    /// file is cut into runs of whole frames served as packed audio segments,
+    /// each behind the ID3 timestamp tag RFC 8216 section 3.4 requires.
+    /// `?packed=false` addresses the runs with `#EXT-X-BYTERANGE` into
+    /// `/tracks/{id}/stream` instead: lighter, and played by common players,
+    /// but not conformant since the raw slices carry no timestamp tag.
     pub async fn serve_hls_playlist(
-        mut socket: TcpStream,
         request: Request,
-    ) -> std::io::Result<()> {
-        let song = match &request.params {
-            Some(params) => match params.get("song") {
-                Some(song) => song,
-                None => {
-                    let _ = socket
-                        .write_all(b"HTTP/1.1 404 Not Found\r\n\r\n404 Not Found")
-                        .await;
-                    return Ok(());
-                }
-            },
-            None => {
-                let _ = socket
-                    .write_all(b"HTTP/1.1 404 Not Found\r\n\r\n404 Not Found")
-                    .await;
-                return Ok(());
-            }
-        };
-        let decoded_song = percent_encoding::percent_decode(song.as_bytes())
-            .decode_utf8()
-            .expect("error decode percent");
+        params: Params,
+        state: AppState,
+    ) -> Result<Response, ApiError> {
+        let track = TrackService::resolve(&request, &params, &state).await?;
+        let song = track.title;
+        let track_id = track.track_id.unwrap_or_default();
 
-        println!("received {} dedode {}", song, decoded_song);
+        println!("received {}", song);
 
-        let path = format!("./mp3/{}.mp3", decoded_song);
+        let path = safe_path::resolve(&state.config.storage.mp3_dir, &track.mp3_path).await?;
         let metadata = match tokio::fs::metadata(&path).await {
             Ok(metadata) => metadata,
-            Err(_) => {
-                let _ = socket
-                    .write_all(b"HTTP/1.1 404 Not Found\r\n\r\n404 Not Found")
-                    .await;
-                return Ok(());
-            }
+            Err(_) => return Err(ApiError::not_found(format!("song `{}` not found", song))),
         };
+        let file_validators = Validators::new(&metadata);
+        let hls_time = state.config.encoding.hls_time as f64;
+        let packed = query_param(&request, "packed").map_or(true, |packed| packed != "false");
+        let validators = file_validators.derived(&format!(
+            "mp3.m3u8 track={} hls_time={} packed={}",
+            track_id, hls_time, packed
+        ));
+        if validators.not_modified(&request) {
+            return Ok(validators
+                .not_modified_response()
+                .header("Cache-Control", "public, max-age=300"));
+        }
 
-        let file_size = metadata.len();
-
-        // Calculate segments (10 seconds each, approximate)
-        let segment_duration = 10.0; // seconds
-        let approx_bitrate = 128000; // 128 kbps
-        let bytes_per_second = approx_bitrate / 8; // bits to bytes
-        let segment_size = (bytes_per_second as f64 * segment_duration) as u64;
-
-        let num_segments = (file_size + segment_size - 1) / segment_size;
-
-        // Generate M3U8 playlist
-        let mut playlist = String::new();
-        playlist.push_str("#EXTM3U\n");
-        playlist.push_str("#EXT-X-VERSION:3\n");
-        playlist.push_str("#EXT-X-TARGETDURATION:11\n");
-        playlist.push_str("#EXT-X-MEDIA-SEQUENCE:0\n");
-
-        for i in 0..num_segments {
-            let start = i * segment_size;
-            let end = std::cmp::min(start + segment_size - 1, file_size - 1);
-            let actual_duration = if i == num_segments - 1 {
-                // Last segment might be shorter
-                let remaining_bytes = file_size - start;
-                (remaining_bytes as f64 / bytes_per_second as f64).min(segment_duration)
+        let segments = state
+            .mp3_segments
+            .get(&path, &file_validators, hls_time)
+            .await?;
+
+        // Byte ranges need version 4
+        let mut playlist = MediaPlaylist {
+            version: Some(if packed { 3 } else { 4 }),
+            target_duration: segments
+                .iter()
+                .map(|segment| segment.duration.round() as u64)
+                .max()
+                .unwrap_or_default(),
+            playlist_type: Some(PlaylistType::Vod),
+            end_list: true,
+            ..Default::default()
+        };
+        for range in segments.iter() {
+            let segment = if packed {
+                let end = range.offset + range.length - 1;
+                Segment {
+                    uri: format!(
+                        "/tracks/{}/mp3-segment?start={}&end={}",
+                        track_id, range.offset, end
+                    ),
+                    ..Default::default()
+                }
             } else {
-                segment_duration
+                Segment {
+                    byte_range: Some(ByteRange {
+                        length: range.length,
+                        offset: Some(range.offset),
+                    }),
+                    uri: format!("/tracks/{}/stream", track_id),
+                    ..Default::default()
+                }
             };
-
-            playlist.push_str(&format!("#EXTINF:{:.1},\n", actual_duration));
-            println!("segment push");
-            playlist.push_str(&format!(
-                "/segment?song={}&start={}&end={}\n",
-                song, start, end
-            ));
+            playlist.segments.push(Segment {
+                duration: range.duration,
+                ..segment
+            });
         }
 
-        playlist.push_str("#EXT-X-ENDLIST\n");
-
-        // Send response
-        let response = format!(
-            "HTTP/1.1 200 OK\r\n\
-        Content-Type: application/vnd.apple.mpegurl\r\n\
-        Content-Length: {}\r\n\
-        Access-Control-Allow-Origin: *\r\n\
-        Cache-Control: no-cache\r\n\
-        \r\n{}",
-            playlist.len(),
-            playlist
-        );
-
-        socket.write_all(response.as_bytes()).await?;
-        Ok(())
+        Ok(validators.apply(
+            Response::ok()
+                .header("Cache-Control", "public, max-age=300")
+                .bytes(CONTENT_TYPE_M3U8, playlist.to_string().into_bytes()),
+        ))
     }
 
     // HLS segment handler
-    pub async fn serve_hls_segment(mut socket: TcpStream, request: Request) -> std::io::Result<()> {
-        let params = match &request.params {
-            Some(params) => params,
-            None => {
-                let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
-                return Ok(());
-            }
-        };
+    pub async fn serve_hls_segment(
+        request: Request,
+        params: Params,
+        state: AppState,
+    ) -> Result<Response, ApiError> {
+        let track = TrackService::resolve(&request, &params, &state).await?;
+        let song = track.title;
 
-        let song_encoded = match params.get("song") {
-            Some(song) => song,
-            None => {
-                let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
-                return Ok(());
-            }
-        };
+        println!("received {}", song);
 
-        let song = percent_encoding::percent_decode(song_encoded.as_bytes())
-            .decode_utf8()
-            .expect("error decode percent");
+        let start: u64 = query_param(&request, "start")?
+            .parse()
+            .map_err(|_| ApiError::bad_request("`start` must be a byte offset"))?;
 
-        println!("received {} dedode {}", song_encoded, song);
+        let end: u64 = query_param(&request, "end")?
+            .parse()
+            .map_err(|_| ApiError::bad_request("`end` must be a byte offset"))?;
 
-        let start: u64 = match params.get("start") {
-            Some(start_str) => match start_str.parse() {
-                Ok(start) => start,
-                Err(_) => {
-                    let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
-                    return Ok(());
-                }
-            },
-            None => {
-                let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
-                return Ok(());
-            }
+        let path = safe_path::resolve(&state.config.storage.mp3_dir, &track.mp3_path).await?;
+        let file = match File::open(&path).await {
+            Ok(file) => file,
+            Err(_) => return Err(ApiError::not_found(format!("song `{}` not found", song))),
         };
 
-        let end: u64 = match params.get("end") {
-            Some(end_str) => match end_str.parse() {
-                Ok(end) => end,
-                Err(_) => {
-                    let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
-                    return Ok(());
-                }
-            },
-            None => {
-                let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
-                return Ok(());
-            }
-        };
+        let metadata = file.metadata().await?;
+        if start > end || end >= metadata.len() {
+            return Err(ApiError::bad_request("invalid segment range"));
+        }
+        let file_validators = Validators::new(&metadata);
+        let validators = file_validators.derived(&format!("mp3-segment {}-{}", start, end));
+
+        // Only segments listed by `serve_hls_playlist`, whose start time is
+        // needed for the timestamp tag
+        let hls_time = state.config.encoding.hls_time as f64;
+        let segments = state
+            .mp3_segments
+            .get(&path, &file_validators, hls_time)
+            .await?;
+        let index = segments
+            .iter()
+            .position(|range| range.offset == start && range.offset + range.length - 1 == end)
+            .ok_or_else(|| ApiError::not_found(format!("no segment {}-{}", start, end)))?;
+        let start_secs = segments[..index].iter().map(|range| range.duration).sum();
+
+        // Packed MP3 audio, as listed by `serve_hls_playlist`
+        Ok(FileServer::serve_slice(
+            &request,
+            file,
+            &validators,
+            CONTENT_TYPE_MP3,
+            &timestamp_tag(start_secs),
+            start,
+            end - start + 1,
+        )
+        .header("Cache-Control", "public, max-age=86400"))
+    }
 
-        let path = format!("./mp3/{}.mp3", song);
-        let mut file = match File::open(&path).await {
-            Ok(file) => file,
-            Err(_) => {
-                let _ = socket.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await;
-                return Ok(());
-            }
-        };
+    // Updated HLS playlist handler - serves pre-generated m3u8 files
+    pub async fn serve_hls_playlist1(
+        request: Request,
+        params: Params,
+        state: AppState,
+    ) -> Result<Response, ApiError> {
+        let track = TrackService::resolve(&request, &params, &state).await?;
+        let relative = Path::new(&track.hls_playlist).to_path_buf();
+        Self::serve_playlist(&request, &track, &relative, &state).await
+    }
 
-        let content_length = end - start + 1;
+    // Media playlist of one rendition, listed in the master playlist
+    pub async fn serve_hls_variant(
+        request: Request,
+        params: Params,
+        state: AppState,
+    ) -> Result<Response, ApiError> {
+        let track = TrackService::resolve(&request, &params, &state).await?;
+        let variant = params
+            .get("file")
+            .ok_or_else(|| ApiError::bad_request("missing variant"))?;
+        if !variant.ends_with(".m3u8") {
+            return Err(ApiError::not_found(format!("no variant `{}`", variant)));
+        }
 
-        // Seek to start position
-        file.seek(SeekFrom::Start(start)).await?;
+        // Variant playlists sit next to the master playlist
+        let relative = Path::new(&track.hls_playlist).with_file_name(variant);
+        Self::serve_playlist(&request, &track, &relative, &state).await
+    }
 
-        // Read segment data
-        let mut buffer = vec![0; content_length as usize];
-        file.read_exact(&mut buffer).await?;
+    /// DASH manifest of the pre-generated renditions, with the segment
+    /// durations of their HLS media playlists. Only tracks converted with
+    /// fMP4 segments have one.
+    pub async fn serve_dash_manifest(
+        request: Request,
+        params: Params,
+        state: AppState,
+    ) -> Result<Response, ApiError> {
+        let track = TrackService::resolve(&request, &params, &state).await?;
+        let track_id = track.track_id.unwrap_or_default();
+        if track.segment_format != SegmentFormat::Fmp4 {
+            return Err(ApiError::conflict(format!(
+                "track {} has MPEG-TS segments, which DASH players cannot play; \
+                 only fmp4 tracks have a DASH manifest",
+                track_id
+            )));
+        }
 
-        // Send segment as MPEG-TS (for HLS compatibility)
-        let response = format!(
-            "HTTP/1.1 200 OK\r\n\
-        Content-Type: video/mp2t\r\n\
-        Content-Length: {}\r\n\
-        Access-Control-Allow-Origin: *\r\n\
-        Cache-Control: public, max-age=86400\r\n\
-        \r\n",
-            content_length
-        );
+        let master_path = Path::new(&track.hls_playlist);
+        let (playlist, master_validators) =
+            Self::read_playlist(&track, master_path, &state).await?;
+
+        // The manifest is made of the master and every media playlist, so its
+        // validators cover all of them
+        let mut inputs = format!("manifest.mpd track={}", track_id);
+        let mut last_modified = master_validators.last_modified;
+        let mut representations = Vec::new();
+        match playlist {
+            Playlist::Master(master) => {
+                for variant in master.variants {
+                    let relative = master_path.with_file_name(&variant.uri);
+                    let (Playlist::Media(playlist), variant_validators) =
+                        Self::read_playlist(&track, &relative, &state).await?
+                    else {
+                        return Err(anyhow!("{} is not a media playlist", variant.uri).into());
+                    };
+                    inputs.push_str(&format!(" {}={}", variant.uri, variant_validators.etag));
+                    last_modified = last_modified.max(variant_validators.last_modified);
+                    representations.push(Representation {
+                        id: variant.uri.trim_end_matches(".m3u8").to_string(),
+                        bandwidth: variant.bandwidth,
+                        codecs: variant.codecs,
+                        playlist,
+                    });
+                }
+            }
+            // Single rendition of a track converted before multi-bitrate HLS
+            Playlist::Media(playlist) => {
+                let bandwidth = Self::measure_bandwidth(&playlist, master_path, &state).await?;
+                representations.push(Representation {
+                    id: "0".to_string(),
+                    bandwidth,
+                    codecs: None,
+                    playlist,
+                });
+            }
+        }
+        let validators = Validators {
+            last_modified,
+            ..master_validators.derived(&inputs)
+        };
+        if validators.not_modified(&request) {
+            return Ok(validators
+                .not_modified_response()
+                .header("Cache-Control", "public, max-age=300"));
+        }
 
-        socket.write_all(response.as_bytes()).await?;
-        socket.write_all(&buffer).await?;
+        for representation in &mut representations {
+            representation
+                .playlist
+                .map_uris(|uri| Self::segment_url(track_id, uri));
+        }
 
-        Ok(())
+        Ok(validators.apply(
+            Response::ok()
+                .header("Cache-Control", "public, max-age=300")
+                .bytes(
+                    CONTENT_TYPE_MPD,
+                    dash::manifest(&representations).into_bytes(),
+                ),
+        ))
     }
 
-    // Updated HLS playlist handler - serves pre-generated m3u8 files
-    pub async fn serve_hls_playlist1(
-        mut socket: TcpStream,
-        request: Request,
-    ) -> std::io::Result<()> {
-        let song_enc = match &request.params {
-            Some(params) => match params.get("song") {
-                Some(song) => song,
+    // Average bitrate of a media playlist, from the size of its segments.
+    async fn measure_bandwidth(
+        playlist: &MediaPlaylist,
+        relative: &Path,
+        state: &AppState,
+    ) -> Result<u64, ApiError> {
+        let mut bytes = 0;
+        for segment in &playlist.segments {
+            bytes += match segment.byte_range {
+                Some(range) => range.length,
                 None => {
-                    let _ = socket
-                        .write_all(b"HTTP/1.1 404 Not Found\r\n\r\n404 Not Found")
-                        .await;
-                    return Ok(());
+                    let segment_path = relative.with_file_name(&segment.uri);
+                    let path =
+                        safe_path::resolve(&state.config.storage.hls_dir, segment_path).await?;
+                    tokio::fs::metadata(&path).await?.len()
                 }
-            },
-            None => {
-                let _ = socket
-                    .write_all(b"HTTP/1.1 404 Not Found\r\n\r\n404 Not Found")
-                    .await;
-                return Ok(());
-            }
-        };
-        let song = percent_encoding::percent_decode(song_enc.as_bytes())
-            .decode_utf8()
-            .expect("error decode percent");
+            };
+        }
+        let secs = playlist.duration().max(0.001);
+        Ok((bytes as f64 * 8.0 / secs).ceil() as u64)
+    }
 
-        println!("received {} dedode {}", song_enc, song);
+    // Serves a pre-generated playlist with its URIs pointing at our endpoints
+    async fn serve_playlist(
+        request: &Request,
+        track: &Track,
+        relative: &Path,
+        state: &AppState,
+    ) -> Result<Response, ApiError> {
+        let (mut playlist, validators) = Self::read_playlist(track, relative, state).await?;
+        if validators.not_modified(request) {
+            return Ok(validators
+                .not_modified_response()
+                .header("Cache-Control", "public, max-age=300"));
+        }
 
+        // Modify the playlist to use our segment endpoint
+        Self::modify_playlist_urls(&mut playlist, track);
+
+        Ok(validators.apply(
+            Response::ok()
+                .header("Cache-Control", "public, max-age=300")
+                .bytes(CONTENT_TYPE_M3U8, playlist.to_string().into_bytes()),
+        ))
+    }
+
+    // Reads and parses a pre-generated playlist under `storage.hls_dir`.
+    async fn read_playlist(
+        track: &Track,
+        relative: &Path,
+        state: &AppState,
+    ) -> Result<(Playlist, Validators), ApiError> {
         // Path to the pre-generated m3u8 file
-        let playlist_path = format!("./hls/{}/{}.m3u8", song, song);
-
-        // Check if the playlist file exists
-        if !Path::new(&playlist_path).exists() {
-            println!("gada ketemu {}", playlist_path);
-            let _ = socket
-                .write_all(b"HTTP/1.1 404 Not Found\r\n\r\n404 Not Found")
-                .await;
-            return Ok(());
-        }
+        let playlist_path = safe_path::resolve(&state.config.storage.hls_dir, relative).await?;
 
         // Read the existing m3u8 file
         let mut file = match File::open(&playlist_path).await {
             Ok(file) => file,
             Err(_) => {
-                let _ = socket
-                    .write_all(b"HTTP/1.1 404 Not Found\r\n\r\n404 Not Found")
-                    .await;
-                return Ok(());
+                let message = format!("no playlist for `{}`", track.title);
+                return Err(ApiError::not_found(message));
             }
         };
+        let validators = Validators::new(&file.metadata().await?);
 
         let mut playlist_content = String::new();
-        if let Err(_) = file.read_to_string(&mut playlist_content).await {
-            let _ = socket
-                .write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n500 Internal Server Error")
-                .await;
-            return Ok(());
-        }
-
-        // Modify the playlist to use our segment endpoint
-        let modified_playlist = Self::modify_playlist_urls(&playlist_content, &song);
-        println!("{:?}", modified_playlist);
-
-        // Send response
-        // let response = format!(
-        //     "HTTP/1.1 200 OK\r\n\
-        // Content-Type: application/vnd.apple.mpegurl\r\n\
-        // Content-Length: {}\r\n\
-        // Access-Control-Allow-Origin: *\r\n\
-        // Cache-Control: no-cache\r\n\
-        // \r\n{}",
-        //     modified_playlist.len(),
-        //     modified_playlist
-        // );
-        let response = format!(
-            "HTTP/1.1 200 OK\r\n\
-    Content-Type: application/vnd.apple.mpegurl\r\n\
-    Content-Length: {}\r\n\
-    Access-Control-Allow-Origin: *\r\n\
-    Cache-Control: public, max-age=300\r\n\
-    \r\n{}",
-            modified_playlist.len(),
-            modified_playlist
-        );
-
-        socket.write_all(response.as_bytes()).await?;
-        Ok(())
+        file.read_to_string(&mut playlist_content).await?;
+        let playlist = Playlist::parse(&playlist_content)
+            .with_context(|| format!("invalid playlist {}", playlist_path.display()))?;
+        Ok((playlist, validators))
     }
 
-    // Helper function to modify playlist URLs to point to our segment handler
-    fn modify_playlist_urls(playlist: &str, song: &str) -> String {
-        let mut modified = String::new();
-
-        for line in playlist.lines() {
-            if line.ends_with(".ts") {
-                // Extract the segment filename
-                let segment_name = line.trim();
-                // Replace with our segment endpoint
-                modified.push_str(&format!("/segment?song={}&file={}\n", song, segment_name));
-            } else {
-                modified.push_str(line);
-                modified.push('\n');
-            }
+    // Helper function to modify playlist URLs to point to our segment and
+    // variant handlers
+    fn modify_playlist_urls(playlist: &mut Playlist, track: &Track) {
+        let track_id = track.track_id.unwrap_or_default();
+        match playlist {
+            // Renditions of a master playlist
+            Playlist::Master(master) => master.map_uris(|uri| {
+                let variant_name = utf8_percent_encode(uri, SEGMENT_NAME);
+                format!("/tracks/{}/variants/{}", track_id, variant_name)
+            }),
+            Playlist::Media(media) => media.map_uris(|uri| Self::segment_url(track_id, uri)),
         }
+    }
 
-        modified
+    fn segment_url(track_id: i32, uri: &str) -> String {
+        let segment_name = utf8_percent_encode(uri, SEGMENT_NAME);
+        format!("/tracks/{}/segments/{}", track_id, segment_name)
     }
 
-    // Updated HLS segment handler - serves pre-generated .ts files
+    // Updated HLS segment handler - serves pre-generated .ts segments, or the
+    // init section and .m4s fragments of fMP4 renditions
     pub async fn serve_hls_segment1(
-        mut socket: TcpStream,
         request: Request,
-    ) -> std::io::Result<()> {
-        let params = match &request.params {
-            Some(params) => params,
-            None => {
-                let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
-                return Ok(());
-            }
-        };
-
-        let song_enc = match params.get("song") {
-            Some(song) => song,
-            None => {
-                let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
-                return Ok(());
-            }
+        params: Params,
+        state: AppState,
+    ) -> Result<Response, ApiError> {
+        let track = TrackService::resolve(&request, &params, &state).await?;
+        let segment_file = match params.get("file") {
+            Some(file) => file.clone(),
+            None => query_param(&request, "file")?,
         };
 
-        let song = percent_encoding::percent_decode(song_enc.as_bytes())
-            .decode_utf8()
-            .expect("error decode percent");
-
-        println!("received {} dedode {}", song_enc, song);
-
-        let segment_file_enc = match params.get("file") {
-            Some(file) => file,
-            None => {
-                let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
-                return Ok(());
+        // Only the files of the container the track was converted with
+        let extension = Path::new(&segment_file)
+            .extension()
+            .and_then(|extension| extension.to_str());
+        let content_type = match (track.segment_format, extension) {
+            (SegmentFormat::Ts, Some("ts")) => CONTENT_TYPE_TS,
+            (SegmentFormat::Fmp4, Some("m4s" | "mp4")) => CONTENT_TYPE_MP4,
+            _ => {
+                return Err(ApiError::not_found(format!(
+                    "no segment `{}`",
+                    segment_file
+                )));
             }
         };
 
-        let segment_file = percent_encoding::percent_decode(segment_file_enc.as_bytes())
-            .decode_utf8()
-            .expect("error decode percent");
+        // Segments sit next to the playlist
+        let relative = Path::new(&track.hls_playlist).with_file_name(&segment_file);
+        let segment_path = safe_path::resolve(&state.config.storage.hls_dir, relative).await?;
 
-        println!("received {} dedode {}", segment_file_enc, segment_file);
-
-        // Path to the pre-generated .ts file
-        let segment_path = format!("./hls/{}/{}", song, segment_file);
-
-        // Check if the segment file exists
-        if !Path::new(&segment_path).exists() {
-            let _ = socket.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await;
-            return Ok(());
-        }
-
-        // Read the .ts file
-        let mut file = match File::open(&segment_path).await {
-            Ok(file) => file,
-            Err(_) => {
-                let _ = socket.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await;
-                return Ok(());
-            }
-        };
-
-        let mut buffer = Vec::new();
-        if let Err(_) = file.read_to_end(&mut buffer).await {
-            let _ = socket
-                .write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n")
-                .await;
-            return Ok(());
-        }
-
-        // Send the pre-generated .ts segment
-        let response = format!(
-            "HTTP/1.1 200 OK\r\n\
-        Content-Type: video/mp2t\r\n\
-        Content-Length: {}\r\n\
-        Access-Control-Allow-Origin: *\r\n\
-        Cache-Control: public, max-age=86400\r\n\
-        \r\n",
-            buffer.len()
-        );
+        // Send the pre-generated segment
+        Ok(FileServer::serve(&request, &segment_path, content_type)
+            .await?
+            .header("Cache-Control", "public, max-age=86400"))
+    }
+}
 
-        socket.write_all(response.as_bytes()).await?;
-        socket.write_all(&buffer).await?;
+// ID3v2.4 tag with the PRIV frame every packed audio segment starts with
+// (RFC 8216 section 3.4): the 33-bit, 90 kHz timestamp of its first sample.
+fn timestamp_tag(start_secs: f64) -> Vec<u8> {
+    const OWNER: &[u8] = b"com.apple.streaming.transportStreamTimestamp\0";
+    let timestamp = (start_secs * 90_000.0).round() as u64 & ((1 << 33) - 1);
+    let frame_len = OWNER.len() + 8;
+
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend(syncsafe(10 + frame_len));
+    tag.extend(b"PRIV");
+    tag.extend(syncsafe(frame_len));
+    tag.extend([0, 0]);
+    tag.extend(OWNER);
+    tag.extend(timestamp.to_be_bytes());
+    tag
+}
 
-        Ok(())
-    }
+// Sizes in ID3v2.4 headers use 7 bits per byte.
+fn syncsafe(size: usize) -> [u8; 4] {
+    [21, 14, 7, 0].map(|shift| ((size >> shift) & 0x7F) as u8)
 }
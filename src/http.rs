@@ -0,0 +1,255 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use request_http_parser::parser::Request;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::error::ApiError;
+
+/// How long a client may take to send the rest of a request once it started.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const MAX_HEADER_SIZE: usize = 16 * 1024;
+pub const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+const READ_CHUNK: usize = 8 * 1024;
+
+/// The request body is larger than `MAX_BODY_SIZE`.
+#[derive(Debug)]
+pub struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request body larger than {} bytes", MAX_BODY_SIZE)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+// A persistent client connection. Bytes read past the end of one request are
+// kept in `buffer` so pipelined requests are not lost.
+pub struct Connection {
+    socket: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    pub fn new(socket: TcpStream) -> Self {
+        Self {
+            socket,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn socket(&mut self) -> &mut TcpStream {
+        &mut self.socket
+    }
+
+    /// Reads the next full request (headers and body) from the connection.
+    ///
+    /// Returns `Ok(None)` when the client closed the connection or stayed idle
+    /// longer than `idle` before sending anything. The boolean tells whether the
+    /// connection may be reused after the response has been written.
+    pub async fn read_request(&mut self, idle: Duration) -> Result<Option<(Request, bool)>> {
+        let head_end = match self.read_head(idle).await? {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let head_bytes: Vec<u8> = self.buffer.drain(..head_end + 4).collect();
+        let head = String::from_utf8_lossy(&head_bytes[..head_end]).to_string();
+
+        let mut request = Request::new(&head)?;
+        let keep_alive = Self::is_keep_alive(&head, &request);
+
+        if request
+            .headers
+            .get("expect")
+            .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"))
+        {
+            self.socket
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await
+                .context("Failed to write")?;
+        }
+
+        let body = if request
+            .headers
+            .get("transfer-encoding")
+            .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"))
+        {
+            self.read_chunked_body().await?
+        } else {
+            match request.headers.get("content-length") {
+                Some(len) => {
+                    let len: usize = len.parse().context("invalid Content-Length")?;
+                    if len > MAX_BODY_SIZE {
+                        return Err(BodyTooLarge.into());
+                    }
+                    self.read_exact_body(len).await?
+                }
+                None => Vec::new(),
+            }
+        };
+
+        request.body = if body.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&body).to_string())
+        };
+        Ok(Some((request, keep_alive)))
+    }
+
+    // Fills the buffer until it contains "\r\n\r\n" and returns its offset.
+    async fn read_head(&mut self, idle: Duration) -> Result<Option<usize>> {
+        loop {
+            if let Some(pos) = find(&self.buffer, b"\r\n\r\n") {
+                return Ok(Some(pos));
+            }
+            if self.buffer.len() > MAX_HEADER_SIZE {
+                return Err(anyhow!("request headers too large"));
+            }
+            // Only the wait for the first byte of a request counts as idle time.
+            let wait = if self.buffer.is_empty() {
+                idle
+            } else {
+                READ_TIMEOUT
+            };
+            let n = match timeout(wait, self.fill()).await {
+                Ok(n) => n?,
+                Err(_) if self.buffer.is_empty() => return Ok(None),
+                Err(_) => return Err(anyhow!("timed out reading request headers")),
+            };
+            if n == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(anyhow!("connection closed mid-request"));
+            }
+        }
+    }
+
+    async fn read_exact_body(&mut self, len: usize) -> Result<Vec<u8>> {
+        while self.buffer.len() < len {
+            self.fill_or_fail().await?;
+        }
+        Ok(self.buffer.drain(..len).collect())
+    }
+
+    async fn read_chunked_body(&mut self) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            let size_str = line.split(';').next().unwrap_or_default().trim();
+            let size = match usize::from_str_radix(size_str, 16) {
+                Ok(size) => size,
+                Err(e) if *e.kind() == std::num::IntErrorKind::PosOverflow => {
+                    return Err(BodyTooLarge.into());
+                }
+                Err(e) => return Err(e).context("invalid chunk size"),
+            };
+            if size == 0 {
+                // Skip optional trailers up to the terminating empty line
+                while !self.read_line().await?.is_empty() {}
+                return Ok(body);
+            }
+            // The size comes from the client; compare without overflowing
+            if size > MAX_BODY_SIZE.saturating_sub(body.len()) {
+                return Err(BodyTooLarge.into());
+            }
+            while self.buffer.len() < size + 2 {
+                self.fill_or_fail().await?;
+            }
+            body.extend(self.buffer.drain(..size));
+            if self.buffer.drain(..2).as_slice() != b"\r\n" {
+                return Err(anyhow!("malformed chunk"));
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        loop {
+            if let Some(pos) = find(&self.buffer, b"\r\n") {
+                let line: Vec<u8> = self.buffer.drain(..pos + 2).collect();
+                return Ok(String::from_utf8_lossy(&line[..pos]).to_string());
+            }
+            if self.buffer.len() > MAX_HEADER_SIZE {
+                return Err(anyhow!("chunk header too large"));
+            }
+            self.fill_or_fail().await?;
+        }
+    }
+
+    async fn fill(&mut self) -> std::io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK];
+        let n = self.socket.read(&mut chunk).await?;
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    async fn fill_or_fail(&mut self) -> Result<()> {
+        match timeout(READ_TIMEOUT, self.fill()).await {
+            Ok(Ok(0)) => Err(anyhow!("connection closed mid-request")),
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(anyhow!("timed out reading request body")),
+        }
+    }
+
+    // HTTP/1.1 connections are persistent unless the client opts out,
+    // HTTP/1.0 ones only when the client asks for it.
+    fn is_keep_alive(head: &str, request: &Request) -> bool {
+        let http10 = head
+            .lines()
+            .next()
+            .is_some_and(|line| line.trim_end().ends_with("HTTP/1.0"));
+        match request.headers.get("connection") {
+            Some(v) if v.eq_ignore_ascii_case("close") => false,
+            Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+            _ => !http10,
+        }
+    }
+}
+
+/// Percent-decoded value of a query parameter, or a `400` when it is missing.
+pub fn query_param(request: &Request, name: &str) -> Result<String, ApiError> {
+    let raw = request
+        .params
+        .as_ref()
+        .and_then(|params| params.get(name))
+        .ok_or_else(|| ApiError::bad_request(format!("missing query parameter `{}`", name)))?;
+    percent_encoding::percent_decode(raw.as_bytes())
+        .decode_utf8()
+        .map(|value| value.to_string())
+        .map_err(|_| ApiError::bad_request(format!("query parameter `{}` is not UTF-8", name)))
+}
+
+/// Deserialises the JSON request body, or a `400` describing what is wrong with it.
+pub fn json_body<T: DeserializeOwned>(request: &Request) -> Result<T, ApiError> {
+    let body = request
+        .body
+        .as_deref()
+        .ok_or_else(|| ApiError::bad_request("missing request body"))?;
+    serde_json::from_str(body)
+        .map_err(|e| ApiError::bad_request(format!("invalid request body: {}", e)))
+}
+
+/// Formats a timestamp as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Parses an IMF-fixdate as sent in `If-Range` or `If-Modified-Since`.
+pub fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
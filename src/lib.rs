@@ -1,9 +1,26 @@
-pub mod constants;
+pub mod clip;
+pub mod config;
+pub mod dash;
 pub mod db;
+pub mod downloader;
+pub mod error;
+pub mod events;
 pub mod file;
+pub mod file_server;
 pub mod hls;
+pub mod http;
+pub mod m3u8;
 pub mod model;
+pub mod mp3;
+pub mod process;
+pub mod progress;
+pub mod range;
 pub mod repo;
+pub mod response;
+pub mod router;
+pub mod safe_path;
 pub mod server;
 pub mod stream;
 pub mod track;
+pub mod transcoder;
+pub mod worker;
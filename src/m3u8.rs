@@ -0,0 +1,702 @@
+use std::fmt;
+
+use anyhow::{Context, anyhow, bail};
+
+/// A parsed HLS playlist (RFC 8216).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Playlist {
+    Master(MasterPlaylist),
+    Media(MediaPlaylist),
+}
+
+impl Playlist {
+    /// Parses a master or media playlist. Tags this module does not model are
+    /// kept verbatim so they survive a round trip.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty());
+        match lines.next() {
+            Some((_, "#EXTM3U")) => {}
+            _ => bail!("playlist does not start with #EXTM3U"),
+        }
+        let lines: Vec<(usize, &str)> = lines.collect();
+        let is_master = lines.iter().any(|(_, line)| {
+            line.starts_with("#EXT-X-STREAM-INF:") || line.starts_with("#EXT-X-MEDIA:")
+        });
+        if is_master {
+            MasterPlaylist::parse_lines(&lines).map(Playlist::Master)
+        } else {
+            MediaPlaylist::parse_lines(&lines).map(Playlist::Media)
+        }
+    }
+}
+
+impl fmt::Display for Playlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Playlist::Master(master) => master.fmt(f),
+            Playlist::Media(media) => media.fmt(f),
+        }
+    }
+}
+
+/// Playlist listing the renditions of a track.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MasterPlaylist {
+    pub version: Option<u32>,
+    pub independent_segments: bool,
+    /// `#EXT-X-MEDIA` renditions.
+    pub media: Vec<Attributes>,
+    pub variants: Vec<VariantStream>,
+    /// Other tags, as written.
+    pub tags: Vec<String>,
+}
+
+/// `#EXT-X-STREAM-INF` and the playlist URI following it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VariantStream {
+    /// Peak bits per second.
+    pub bandwidth: u64,
+    pub average_bandwidth: Option<u64>,
+    pub codecs: Option<String>,
+    /// Attributes other than the above, in order.
+    pub attributes: Attributes,
+    pub uri: String,
+}
+
+impl MasterPlaylist {
+    /// Replaces the URI of every variant and `#EXT-X-MEDIA` rendition.
+    pub fn map_uris(&mut self, mut f: impl FnMut(&str) -> String) {
+        for variant in &mut self.variants {
+            variant.uri = f(&variant.uri);
+        }
+        for media in &mut self.media {
+            if let Some(uri) = media.get("URI") {
+                let uri = f(uri);
+                media.set("URI", AttributeValue::Quoted(uri));
+            }
+        }
+    }
+
+    fn parse_lines(lines: &[(usize, &str)]) -> anyhow::Result<Self> {
+        let mut playlist = MasterPlaylist::default();
+        let mut pending: Option<VariantStream> = None;
+        for &(number, line) in lines {
+            let parsed = (|| -> anyhow::Result<()> {
+                let Some(tag) = line.strip_prefix('#') else {
+                    let mut variant = pending
+                        .take()
+                        .ok_or_else(|| anyhow!("URI `{}` without #EXT-X-STREAM-INF", line))?;
+                    variant.uri = line.to_string();
+                    playlist.variants.push(variant);
+                    return Ok(());
+                };
+                let (name, value) = tag.split_once(':').unwrap_or((tag, ""));
+                match name {
+                    "EXT-X-VERSION" => playlist.version = Some(parse_number(value)?),
+                    "EXT-X-INDEPENDENT-SEGMENTS" => playlist.independent_segments = true,
+                    "EXT-X-MEDIA" => playlist.media.push(Attributes::parse(value)?),
+                    "EXT-X-STREAM-INF" => {
+                        let mut attributes = Attributes::parse(value)?;
+                        let bandwidth = attributes
+                            .remove("BANDWIDTH")
+                            .ok_or_else(|| anyhow!("#EXT-X-STREAM-INF without BANDWIDTH"))?;
+                        let average_bandwidth = attributes
+                            .remove("AVERAGE-BANDWIDTH")
+                            .map(|value| parse_number(&value))
+                            .transpose()?;
+                        pending = Some(VariantStream {
+                            bandwidth: parse_number(&bandwidth)?,
+                            average_bandwidth,
+                            codecs: attributes.remove("CODECS"),
+                            attributes,
+                            uri: String::new(),
+                        });
+                    }
+                    _ if tag.starts_with("EXT") => playlist.tags.push(line.to_string()),
+                    // Comment
+                    _ => {}
+                }
+                Ok(())
+            })();
+            parsed.with_context(|| format!("invalid playlist line {}", number))?;
+        }
+        if pending.is_some() {
+            bail!("#EXT-X-STREAM-INF without a URI");
+        }
+        Ok(playlist)
+    }
+}
+
+impl fmt::Display for MasterPlaylist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "#EXTM3U")?;
+        if let Some(version) = self.version {
+            writeln!(f, "#EXT-X-VERSION:{}", version)?;
+        }
+        if self.independent_segments {
+            writeln!(f, "#EXT-X-INDEPENDENT-SEGMENTS")?;
+        }
+        for tag in &self.tags {
+            writeln!(f, "{}", tag)?;
+        }
+        for media in &self.media {
+            writeln!(f, "#EXT-X-MEDIA:{}", media)?;
+        }
+        for variant in &self.variants {
+            write!(f, "#EXT-X-STREAM-INF:BANDWIDTH={}", variant.bandwidth)?;
+            if let Some(average) = variant.average_bandwidth {
+                write!(f, ",AVERAGE-BANDWIDTH={}", average)?;
+            }
+            if let Some(codecs) = &variant.codecs {
+                write!(f, ",CODECS=\"{}\"", codecs)?;
+            }
+            if !variant.attributes.is_empty() {
+                write!(f, ",{}", variant.attributes)?;
+            }
+            writeln!(f)?;
+            writeln!(f, "{}", variant.uri)?;
+        }
+        Ok(())
+    }
+}
+
+/// Playlist listing the segments of one rendition.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaPlaylist {
+    pub version: Option<u32>,
+    /// Upper bound of segment durations, in whole seconds.
+    pub target_duration: u64,
+    pub media_sequence: u64,
+    pub discontinuity_sequence: Option<u64>,
+    pub playlist_type: Option<PlaylistType>,
+    pub independent_segments: bool,
+    /// Other header tags, as written.
+    pub tags: Vec<String>,
+    pub segments: Vec<Segment>,
+    pub end_list: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistType {
+    Vod,
+    Event,
+}
+
+/// One media segment and the tags that precede its URI.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Segment {
+    /// `#EXTINF` duration in seconds.
+    pub duration: f64,
+    /// `#EXTINF` title, after the comma.
+    pub title: String,
+    pub byte_range: Option<ByteRange>,
+    pub discontinuity: bool,
+    /// `#EXT-X-KEY` starting at this segment; it applies to every segment
+    /// after it until the next one.
+    pub key: Option<Key>,
+    /// `#EXT-X-MAP` starting at this segment, like `key`.
+    pub map: Option<Map>,
+    /// `#EXT-X-PROGRAM-DATE-TIME`, as written.
+    pub program_date_time: Option<String>,
+    /// Other segment tags, as written.
+    pub tags: Vec<String>,
+    pub uri: String,
+}
+
+/// `<length>[@<offset>]`; without an offset the range follows the previous
+/// one of the same resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub length: u64,
+    pub offset: Option<u64>,
+}
+
+impl ByteRange {
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        let (length, offset) = match value.split_once('@') {
+            Some((length, offset)) => (length, Some(parse_number(offset)?)),
+            None => (value, None),
+        };
+        Ok(Self {
+            length: parse_number(length)?,
+            offset,
+        })
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.length)?;
+        if let Some(offset) = self.offset {
+            write!(f, "@{}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// `#EXT-X-KEY`: how the following segments are encrypted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Key {
+    /// `NONE`, `AES-128` or `SAMPLE-AES`.
+    pub method: String,
+    pub uri: Option<String>,
+    /// Attributes other than the above, in order.
+    pub attributes: Attributes,
+}
+
+/// `#EXT-X-MAP`: the initialization section of the following segments.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Map {
+    pub uri: String,
+    pub byte_range: Option<ByteRange>,
+}
+
+impl MediaPlaylist {
+    /// Replaces the URI of every segment, key and initialization section.
+    pub fn map_uris(&mut self, mut f: impl FnMut(&str) -> String) {
+        for segment in &mut self.segments {
+            segment.uri = f(&segment.uri);
+            if let Some(uri) = segment.key.as_mut().and_then(|key| key.uri.as_mut()) {
+                *uri = f(uri);
+            }
+            if let Some(map) = &mut segment.map {
+                map.uri = f(&map.uri);
+            }
+        }
+    }
+
+    /// Sum of the segment durations in seconds.
+    pub fn duration(&self) -> f64 {
+        self.segments.iter().map(|segment| segment.duration).sum()
+    }
+
+    fn parse_lines(lines: &[(usize, &str)]) -> anyhow::Result<Self> {
+        let mut playlist = MediaPlaylist::default();
+        let mut segment = Segment::default();
+        let mut has_duration = false;
+        for &(number, line) in lines {
+            let parsed = (|| -> anyhow::Result<()> {
+                let Some(tag) = line.strip_prefix('#') else {
+                    if !has_duration {
+                        bail!("segment `{}` without #EXTINF", line);
+                    }
+                    segment.uri = line.to_string();
+                    playlist.segments.push(std::mem::take(&mut segment));
+                    has_duration = false;
+                    return Ok(());
+                };
+                let (name, value) = tag.split_once(':').unwrap_or((tag, ""));
+                match name {
+                    "EXT-X-VERSION" => playlist.version = Some(parse_number(value)?),
+                    "EXT-X-TARGETDURATION" => playlist.target_duration = parse_number(value)?,
+                    "EXT-X-MEDIA-SEQUENCE" => playlist.media_sequence = parse_number(value)?,
+                    "EXT-X-DISCONTINUITY-SEQUENCE" => {
+                        playlist.discontinuity_sequence = Some(parse_number(value)?)
+                    }
+                    "EXT-X-PLAYLIST-TYPE" => {
+                        playlist.playlist_type = Some(match value {
+                            "VOD" => PlaylistType::Vod,
+                            "EVENT" => PlaylistType::Event,
+                            _ => bail!("unknown playlist type `{}`", value),
+                        })
+                    }
+                    "EXT-X-INDEPENDENT-SEGMENTS" => playlist.independent_segments = true,
+                    "EXT-X-ENDLIST" => playlist.end_list = true,
+                    "EXTINF" => {
+                        let (duration, title) = value.split_once(',').unwrap_or((value, ""));
+                        segment.duration = duration
+                            .trim()
+                            .parse()
+                            .with_context(|| format!("invalid duration `{}`", duration))?;
+                        segment.title = title.to_string();
+                        has_duration = true;
+                    }
+                    "EXT-X-BYTERANGE" => segment.byte_range = Some(ByteRange::parse(value)?),
+                    "EXT-X-DISCONTINUITY" => segment.discontinuity = true,
+                    "EXT-X-PROGRAM-DATE-TIME" => {
+                        segment.program_date_time = Some(value.to_string())
+                    }
+                    "EXT-X-KEY" => {
+                        let mut attributes = Attributes::parse(value)?;
+                        let method = attributes
+                            .remove("METHOD")
+                            .ok_or_else(|| anyhow!("#EXT-X-KEY without METHOD"))?;
+                        segment.key = Some(Key {
+                            method,
+                            uri: attributes.remove("URI"),
+                            attributes,
+                        });
+                    }
+                    "EXT-X-MAP" => {
+                        let mut attributes = Attributes::parse(value)?;
+                        segment.map = Some(Map {
+                            uri: attributes
+                                .remove("URI")
+                                .ok_or_else(|| anyhow!("#EXT-X-MAP without URI"))?,
+                            byte_range: attributes
+                                .remove("BYTERANGE")
+                                .map(|range| ByteRange::parse(&range))
+                                .transpose()?,
+                        });
+                    }
+                    // Header tags come before the first segment
+                    _ if tag.starts_with("EXT")
+                        && playlist.segments.is_empty()
+                        && !has_duration =>
+                    {
+                        playlist.tags.push(line.to_string())
+                    }
+                    _ if tag.starts_with("EXT") => segment.tags.push(line.to_string()),
+                    // Comment
+                    _ => {}
+                }
+                Ok(())
+            })();
+            parsed.with_context(|| format!("invalid playlist line {}", number))?;
+        }
+        if has_duration {
+            bail!("#EXTINF without a segment URI");
+        }
+        Ok(playlist)
+    }
+}
+
+impl fmt::Display for MediaPlaylist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "#EXTM3U")?;
+        if let Some(version) = self.version {
+            writeln!(f, "#EXT-X-VERSION:{}", version)?;
+        }
+        writeln!(f, "#EXT-X-TARGETDURATION:{}", self.target_duration)?;
+        writeln!(f, "#EXT-X-MEDIA-SEQUENCE:{}", self.media_sequence)?;
+        if let Some(sequence) = self.discontinuity_sequence {
+            writeln!(f, "#EXT-X-DISCONTINUITY-SEQUENCE:{}", sequence)?;
+        }
+        match self.playlist_type {
+            Some(PlaylistType::Vod) => writeln!(f, "#EXT-X-PLAYLIST-TYPE:VOD")?,
+            Some(PlaylistType::Event) => writeln!(f, "#EXT-X-PLAYLIST-TYPE:EVENT")?,
+            None => {}
+        }
+        if self.independent_segments {
+            writeln!(f, "#EXT-X-INDEPENDENT-SEGMENTS")?;
+        }
+        for tag in &self.tags {
+            writeln!(f, "{}", tag)?;
+        }
+        for segment in &self.segments {
+            if let Some(key) = &segment.key {
+                write!(f, "#EXT-X-KEY:METHOD={}", key.method)?;
+                if let Some(uri) = &key.uri {
+                    write!(f, ",URI=\"{}\"", uri)?;
+                }
+                if !key.attributes.is_empty() {
+                    write!(f, ",{}", key.attributes)?;
+                }
+                writeln!(f)?;
+            }
+            if let Some(map) = &segment.map {
+                write!(f, "#EXT-X-MAP:URI=\"{}\"", map.uri)?;
+                if let Some(range) = map.byte_range {
+                    write!(f, ",BYTERANGE=\"{}\"", range)?;
+                }
+                writeln!(f)?;
+            }
+            if segment.discontinuity {
+                writeln!(f, "#EXT-X-DISCONTINUITY")?;
+            }
+            // Six decimals, as ffmpeg writes them
+            writeln!(f, "#EXTINF:{:.6},{}", segment.duration, segment.title)?;
+            if let Some(range) = segment.byte_range {
+                writeln!(f, "#EXT-X-BYTERANGE:{}", range)?;
+            }
+            if let Some(time) = &segment.program_date_time {
+                writeln!(f, "#EXT-X-PROGRAM-DATE-TIME:{}", time)?;
+            }
+            for tag in &segment.tags {
+                writeln!(f, "{}", tag)?;
+            }
+            writeln!(f, "{}", segment.uri)?;
+        }
+        if self.end_list {
+            writeln!(f, "#EXT-X-ENDLIST")?;
+        }
+        Ok(())
+    }
+}
+
+/// Value in an attribute list; quoted strings keep their quotes when written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Quoted(String),
+    Plain(String),
+}
+
+impl AttributeValue {
+    pub fn as_str(&self) -> &str {
+        match self {
+            AttributeValue::Quoted(value) | AttributeValue::Plain(value) => value,
+        }
+    }
+}
+
+/// `NAME=value` pairs of a tag, in the order they were written.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Attributes(pub Vec<(String, AttributeValue)>);
+
+impl Attributes {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut attributes = Vec::new();
+        let mut rest = text.trim();
+        while !rest.is_empty() {
+            let (name, after) = rest
+                .split_once('=')
+                .ok_or_else(|| anyhow!("attribute without a value in `{}`", text))?;
+            let (value, after) = match after.strip_prefix('"') {
+                Some(quoted) => {
+                    let end = quoted
+                        .find('"')
+                        .ok_or_else(|| anyhow!("unterminated quoted string in `{}`", text))?;
+                    let value = AttributeValue::Quoted(quoted[..end].to_string());
+                    (value, &quoted[end + 1..])
+                }
+                None => {
+                    let end = after.find(',').unwrap_or(after.len());
+                    (
+                        AttributeValue::Plain(after[..end].to_string()),
+                        &after[end..],
+                    )
+                }
+            };
+            attributes.push((name.trim().to_string(), value));
+            rest = match after.trim_start().strip_prefix(',') {
+                Some(next) => next.trim_start(),
+                None if after.trim().is_empty() => "",
+                None => bail!("expected `,` after attribute {} in `{}`", name, text),
+            };
+        }
+        Ok(Self(attributes))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replaces the value of `name`, or appends it.
+    pub fn set(&mut self, name: &str, value: AttributeValue) {
+        match self.0.iter_mut().find(|(key, _)| key == name) {
+            Some((_, current)) => *current = value,
+            None => self.0.push((name.to_string(), value)),
+        }
+    }
+
+    fn remove(&mut self, name: &str) -> Option<String> {
+        let index = self.0.iter().position(|(key, _)| key == name)?;
+        match self.0.remove(index).1 {
+            AttributeValue::Quoted(value) | AttributeValue::Plain(value) => Some(value),
+        }
+    }
+}
+
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            match value {
+                AttributeValue::Quoted(value) => write!(f, "{}=\"{}\"", name, value)?,
+                AttributeValue::Plain(value) => write!(f, "{}={}", name, value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> anyhow::Result<T> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("invalid number `{}`", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TS_MEDIA: &str = include_str!("../tests/fixtures/hls/ts_media.m3u8");
+    const FMP4_MEDIA: &str = include_str!("../tests/fixtures/hls/fmp4_media.m3u8");
+    const MASTER: &str = include_str!("../tests/fixtures/hls/master.m3u8");
+    const BYTERANGE_MEDIA: &str = include_str!("../tests/fixtures/hls/byterange_media.m3u8");
+    const KEY_MEDIA: &str = include_str!("../tests/fixtures/hls/key_media.m3u8");
+
+    // Parses `text`, writes it back and checks the result parses to the same playlist.
+    fn round_trip(text: &str) -> Playlist {
+        let playlist = Playlist::parse(text).unwrap();
+        let written = playlist.to_string();
+        assert_eq!(Playlist::parse(&written).unwrap(), playlist, "{}", written);
+        playlist
+    }
+
+    fn media(text: &str) -> MediaPlaylist {
+        match round_trip(text) {
+            Playlist::Media(media) => media,
+            Playlist::Master(_) => panic!("expected a media playlist"),
+        }
+    }
+
+    #[test]
+    fn ts_media_playlist() {
+        let playlist = media(TS_MEDIA);
+        assert_eq!(playlist.version, Some(3));
+        assert_eq!(playlist.target_duration, 10);
+        assert_eq!(playlist.playlist_type, Some(PlaylistType::Vod));
+        assert!(playlist.end_list);
+        assert_eq!(playlist.segments.len(), 4);
+        assert_eq!(playlist.segments[1].duration, 9.984);
+        assert_eq!(playlist.segments[3].uri, "128k_segment_003.ts");
+        assert!((playlist.duration() - 30.986666).abs() < 1e-6);
+        // Written back as ffmpeg wrote it
+        assert_eq!(playlist.to_string(), TS_MEDIA);
+    }
+
+    #[test]
+    fn fmp4_media_playlist() {
+        let playlist = media(FMP4_MEDIA);
+        assert_eq!(playlist.version, Some(7));
+        assert!(playlist.independent_segments);
+        let map = playlist.segments[0].map.as_ref().unwrap();
+        assert_eq!(map.uri, "64k_init.mp4");
+        assert_eq!(map.byte_range, None);
+        assert!(playlist.segments[1..].iter().all(|s| s.map.is_none()));
+        assert_eq!(playlist.to_string(), FMP4_MEDIA);
+    }
+
+    #[test]
+    fn var_stream_map_master_playlist() {
+        let Playlist::Master(master) = round_trip(MASTER) else {
+            panic!("expected a master playlist");
+        };
+        assert_eq!(master.version, Some(3));
+        let variants: Vec<(u64, &str)> = master
+            .variants
+            .iter()
+            .map(|v| (v.bandwidth, v.uri.as_str()))
+            .collect();
+        assert_eq!(
+            variants,
+            [
+                (70400, "64k.m3u8"),
+                (140800, "128k.m3u8"),
+                (281600, "256k.m3u8")
+            ]
+        );
+        assert!(
+            master
+                .variants
+                .iter()
+                .all(|v| v.codecs.as_deref() == Some("mp4a.40.2") && v.attributes.is_empty())
+        );
+    }
+
+    #[test]
+    fn byte_range_media_playlist() {
+        let playlist = media(BYTERANGE_MEDIA);
+        let ranges: Vec<Option<ByteRange>> =
+            playlist.segments.iter().map(|s| s.byte_range).collect();
+        assert_eq!(
+            ranges,
+            [
+                Some(ByteRange {
+                    length: 160928,
+                    offset: Some(0)
+                }),
+                Some(ByteRange {
+                    length: 160364,
+                    offset: Some(160928)
+                }),
+                Some(ByteRange {
+                    length: 16168,
+                    offset: None
+                }),
+            ]
+        );
+        assert!(playlist.segments.iter().all(|s| s.uri == "128k.ts"));
+        assert_eq!(playlist.to_string(), BYTERANGE_MEDIA);
+    }
+
+    #[test]
+    fn encrypted_media_playlist() {
+        let playlist = media(KEY_MEDIA);
+        let key = playlist.segments[0].key.as_ref().unwrap();
+        assert_eq!(key.method, "AES-128");
+        assert_eq!(
+            key.uri.as_deref(),
+            Some("https://keys.example.com/track.key")
+        );
+        assert_eq!(
+            key.attributes.get("IV"),
+            Some("0x0123456789abcdef0123456789abcdef")
+        );
+        assert!(playlist.segments[1].key.is_none());
+        assert!(playlist.segments[2].key.is_some());
+        assert!(playlist.segments[3].discontinuity);
+        assert_eq!(playlist.to_string(), KEY_MEDIA);
+    }
+
+    #[test]
+    fn keeps_unknown_tags() {
+        let text = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-START:TIME-OFFSET=2\n\
+                    #EXTINF:4.000000,intro\n#EXT-X-GAP\na.ts\n";
+        let playlist = media(text);
+        assert_eq!(playlist.tags, ["#EXT-X-START:TIME-OFFSET=2"]);
+        assert_eq!(playlist.segments[0].tags, ["#EXT-X-GAP"]);
+        assert_eq!(playlist.segments[0].title, "intro");
+        assert!(!playlist.end_list);
+    }
+
+    #[test]
+    fn map_uris_rewrites_segments_keys_and_maps() {
+        let mut playlist = media(FMP4_MEDIA);
+        playlist.map_uris(|uri| format!("/segments/{}", uri));
+        assert_eq!(
+            playlist.segments[0].map.as_ref().unwrap().uri,
+            "/segments/64k_init.mp4"
+        );
+        assert_eq!(playlist.segments[3].uri, "/segments/64k_segment_003.m4s");
+
+        let mut playlist = media(KEY_MEDIA);
+        playlist.map_uris(|uri| uri.replace("https://keys.example.com", "/keys"));
+        assert_eq!(
+            playlist.segments[2].key.as_ref().unwrap().uri.as_deref(),
+            Some("/keys/track2.key")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_playlists() {
+        for text in [
+            "",
+            "#EXT-X-VERSION:3\n",
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\nsegment.ts\n",
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXTINF:10,\n",
+            "#EXTM3U\n#EXT-X-TARGETDURATION:ten\n",
+            "#EXTM3U\n#EXT-X-STREAM-INF:CODECS=\"mp4a.40.2\"\n64k.m3u8\n",
+            "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\n",
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-MAP:BYTERANGE=\"1@0\"\n",
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-KEY:URI=\"k\n",
+        ] {
+            assert!(Playlist::parse(text).is_err(), "{:?}", text);
+        }
+    }
+}
@@ -1,5 +1,9 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::clip::{Clip, ClipTime};
+use crate::config::SegmentFormat;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct YtSearchResult {
@@ -29,17 +33,77 @@ pub struct Thumbnail {
 pub struct AddStream {
     pub title: String,
     pub youtube_url: String,
-    pub start: Option<u32>,
-    pub end: Option<u32>,
+    pub start: Option<ClipTime>,
+    pub end: Option<ClipTime>,
+}
+
+impl AddStream {
+    pub fn clip(&self) -> Clip {
+        Clip::new(self.start.map(|s| s.0), self.end.map(|e| e.0))
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct TaskStatus {
-    pub task_id: String,
+    pub task_id: Uuid,
     pub title: String,
-    pub status: String, // downloading, converting, done, failed
-    pub progress: u8,   // 0 to 100
+    pub status: String, // queued, downloading, converting, done, failed, cancelled
+    #[sqlx(try_from = "i16")]
+    pub progress: u8, // 0 to 100
     pub log: Vec<String>,
+    /// Set once the track has been stored.
+    pub track_id: Option<i32>,
+    /// Why the task failed.
+    pub error: Option<String>,
+    // Stage progress as reported by yt-dlp and ffmpeg
+    pub download_percent: Option<f32>,
+    pub download_speed: Option<String>,
+    pub download_eta: Option<String>,
+    pub convert_percent: Option<f32>,
+}
+
+/// Lifecycle of a row in `jobs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Queued,
+    Downloading,
+    Converting,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Downloading => "downloading",
+            JobStatus::Converting => "converting",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// A download claimed by a worker.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Job {
+    pub job_id: Uuid,
+    pub title: String,
+    pub youtube_url: String,
+    pub clip_start: Option<i32>,
+    pub clip_end: Option<i32>,
+    pub attempts: i32,
+}
+
+impl Job {
+    pub fn clip(&self) -> Clip {
+        Clip::new(
+            self.clip_start.map(|s| s as u32),
+            self.clip_end.map(|e| e as u32),
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
@@ -47,11 +111,25 @@ pub struct Track {
     pub track_id: Option<i32>,
     pub title: String,
     pub duration: String,
+    /// Relative to `storage.mp3_dir`.
+    pub mp3_path: String,
+    /// Relative to `storage.hls_dir`; segments live next to it.
+    pub hls_playlist: String,
     pub created_at: DateTime<Utc>,
+    /// URL the track was downloaded from.
+    pub source_url: Option<String>,
+    /// `<extractor>:<id>` of the source video, e.g. `Youtube:dQw4w9WgXcQ`.
+    pub video_id: Option<String>,
+    /// Seconds of the source the track was cut to.
+    pub clip_start: Option<i32>,
+    pub clip_end: Option<i32>,
+    /// Container of its HLS segments.
+    pub segment_format: SegmentFormat,
 }
 
 #[derive(Serialize, Deserialize, sqlx::FromRow, Debug)]
 pub struct GetTrack {
+    pub track_id: i32,
     pub title: String,
     pub duration: String,
 }
@@ -0,0 +1,664 @@
+use std::io::{self, Read};
+
+// Bitrates in kbit/s by bitrate index 1..=14, per version and layer.
+const BITRATES_V1: [[u32; 14]; 3] = [
+    [
+        32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+];
+const BITRATES_V2: [[u32; 14]; 2] = [
+    [
+        32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+const SAMPLE_RATES_V1: [u32; 3] = [44100, 48000, 32000];
+// Longest frame the parser accepts: MPEG-2.5 Layer II at 160 kbit/s and
+// 8 kHz, padded.
+const MAX_FRAME_LEN: usize = 2881;
+// Bytes read at a time when scanning a file.
+const READ_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Mpeg1,
+    Mpeg2,
+    Mpeg25,
+}
+
+/// The 4 byte header every MPEG audio frame starts with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: Version,
+    /// 1, 2 or 3.
+    pub layer: u8,
+    /// Bits per second.
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub padding: bool,
+    pub mono: bool,
+}
+
+impl FrameHeader {
+    /// Reads a header from the start of `bytes`. Free-format and reserved
+    /// values are rejected.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let &[b0, b1, b2, b3] = bytes.get(..4)? else {
+            return None;
+        };
+        if b0 != 0xFF || b1 & 0xE0 != 0xE0 {
+            return None;
+        }
+        let version = match (b1 >> 3) & 0b11 {
+            0 => Version::Mpeg25,
+            2 => Version::Mpeg2,
+            3 => Version::Mpeg1,
+            _ => return None,
+        };
+        let layer = match (b1 >> 1) & 0b11 {
+            1 => 3,
+            2 => 2,
+            3 => 1,
+            _ => return None,
+        };
+        let bitrate_index = (b2 >> 4) as usize;
+        if bitrate_index == 0 || bitrate_index == 15 {
+            return None;
+        }
+        let kbps = match version {
+            Version::Mpeg1 => BITRATES_V1[layer as usize - 1][bitrate_index - 1],
+            // Layers II and III share a table
+            _ => BITRATES_V2[(layer as usize - 1).min(1)][bitrate_index - 1],
+        };
+        let rate_index = ((b2 >> 2) & 0b11) as usize;
+        let base_rate = *SAMPLE_RATES_V1.get(rate_index)?;
+        let sample_rate = match version {
+            Version::Mpeg1 => base_rate,
+            Version::Mpeg2 => base_rate / 2,
+            Version::Mpeg25 => base_rate / 4,
+        };
+        Some(Self {
+            version,
+            layer,
+            bitrate: kbps * 1000,
+            sample_rate,
+            padding: (b2 >> 1) & 1 == 1,
+            mono: b3 >> 6 == 0b11,
+        })
+    }
+
+    /// Samples per channel in the frame.
+    pub fn samples(&self) -> u32 {
+        match (self.layer, self.version) {
+            (1, _) => 384,
+            (3, Version::Mpeg2 | Version::Mpeg25) => 576,
+            _ => 1152,
+        }
+    }
+
+    /// Length of the whole frame in bytes, header included.
+    pub fn frame_len(&self) -> usize {
+        // Layer I pads with 4 byte slots, the others with single bytes
+        let (slot, padding) = match self.layer {
+            1 => (4, 4 * self.padding as u32),
+            _ => (1, self.padding as u32),
+        };
+        let slots = self.samples() / 8 * self.bitrate / slot / self.sample_rate;
+        (slots * slot + padding) as usize
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.samples() as f64 / self.sample_rate as f64
+    }
+
+    // Frames of one stream share these; anything else is a false sync.
+    fn same_stream(&self, other: &FrameHeader) -> bool {
+        self.version == other.version
+            && self.layer == other.layer
+            && self.sample_rate == other.sample_rate
+    }
+
+    // Offset of a Xing/Info tag in the first frame, after the side information.
+    fn xing_offset(&self) -> usize {
+        match (self.version, self.mono) {
+            (Version::Mpeg1, false) => 4 + 32,
+            (Version::Mpeg1, true) | (_, false) => 4 + 17,
+            (_, true) => 4 + 9,
+        }
+    }
+}
+
+/// Summary an encoder writes into an otherwise silent first frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VbrHeader {
+    pub kind: VbrKind,
+    /// Audio frames in the stream, not counting the header frame.
+    pub frames: Option<u32>,
+    /// Bytes of audio in the stream.
+    pub bytes: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VbrKind {
+    /// `Xing`, written by LAME for VBR streams.
+    Xing,
+    /// `Info`, the same written for CBR streams.
+    Info,
+    /// Fraunhofer's `VBRI`.
+    Vbri,
+}
+
+impl VbrHeader {
+    /// Looks for a Xing, Info or VBRI tag in `frame`, the first frame of a
+    /// stream.
+    pub fn parse(header: &FrameHeader, frame: &[u8]) -> Option<Self> {
+        let offset = header.xing_offset();
+        match frame.get(offset..offset + 4)? {
+            tag @ (b"Xing" | b"Info") => {
+                let flags = read_u32(frame, offset + 4)?;
+                let mut at = offset + 8;
+                let mut field = |present: bool| {
+                    if !present {
+                        return None;
+                    }
+                    let value = read_u32(frame, at);
+                    at += 4;
+                    value
+                };
+                let frames = field(flags & 1 != 0);
+                let bytes = field(flags & 2 != 0);
+                Some(Self {
+                    kind: if tag == b"Xing" {
+                        VbrKind::Xing
+                    } else {
+                        VbrKind::Info
+                    },
+                    frames,
+                    bytes,
+                })
+            }
+            // VBRI sits at a fixed offset whatever the channel mode
+            _ if frame.get(36..40)? == b"VBRI" => Some(Self {
+                kind: VbrKind::Vbri,
+                bytes: read_u32(frame, 36 + 10),
+                frames: read_u32(frame, 36 + 14),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// One audio frame of a stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub offset: u64,
+    pub len: u32,
+    pub duration: f64,
+}
+
+/// A run of whole frames, served as one HLS segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameRange {
+    pub offset: u64,
+    pub length: u64,
+    pub duration: f64,
+}
+
+/// The audio frames of an MP3 file, without its tags, found from the frame
+/// headers alone so the file can be cut on frame boundaries without decoding.
+#[derive(Debug, Clone, Default)]
+pub struct Mp3Stream {
+    pub frames: Vec<Frame>,
+    pub vbr: Option<VbrHeader>,
+}
+
+impl Mp3Stream {
+    /// Finds every audio frame in `data`, skipping an ID3v2 tag in front,
+    /// a Xing/Info/VBRI header frame and any junk between frames. Returns
+    /// `None` when there is no audio.
+    pub fn scan(data: &[u8]) -> Option<Self> {
+        Self::read(data).ok().flatten()
+    }
+
+    /// `scan` over a file or any other reader, holding only a few blocks of
+    /// it in memory at a time.
+    pub fn read(reader: impl Read) -> io::Result<Option<Self>> {
+        let mut window = Window::new(reader);
+        let mut stream = Mp3Stream::default();
+        let mut at = id3v2_len(window.get(0, 10)?);
+        let mut synced = false;
+        loop {
+            // The frame and the header after it
+            let data = window.get(at, MAX_FRAME_LEN + 4)?;
+            if data.len() < 4 {
+                break;
+            }
+            let header = match FrameHeader::parse(data) {
+                Some(header) if header.frame_len() <= data.len() => header,
+                _ => {
+                    at += 1;
+                    synced = false;
+                    continue;
+                }
+            };
+            let len = header.frame_len();
+            // Off sync, a header only counts when the next frame follows it
+            if !synced {
+                let next = &data[len..];
+                let confirmed = match FrameHeader::parse(next) {
+                    Some(next) => header.same_stream(&next),
+                    None => next.len() < 4 || next.starts_with(b"TAG"),
+                };
+                if !confirmed {
+                    at += 1;
+                    continue;
+                }
+                synced = true;
+            }
+            if stream.frames.is_empty() && stream.vbr.is_none() {
+                stream.vbr = VbrHeader::parse(&header, &data[..len]);
+                if stream.vbr.is_some() {
+                    at += len as u64;
+                    continue;
+                }
+            }
+            stream.frames.push(Frame {
+                offset: at,
+                len: len as u32,
+                duration: header.duration(),
+            });
+            at += len as u64;
+        }
+        Ok((!stream.frames.is_empty()).then_some(stream))
+    }
+
+    /// Length of the audio in seconds.
+    pub fn duration(&self) -> f64 {
+        self.frames.iter().map(|frame| frame.duration).sum()
+    }
+
+    /// Groups the frames into runs of about `target_secs` each; a run ends at
+    /// the first frame boundary past the target. Junk between frames stays
+    /// inside the run it falls in.
+    pub fn segments(&self, target_secs: f64) -> Vec<FrameRange> {
+        let mut segments: Vec<FrameRange> = Vec::new();
+        let mut current: Option<FrameRange> = None;
+        for frame in &self.frames {
+            let range = current.get_or_insert(FrameRange {
+                offset: frame.offset,
+                length: 0,
+                duration: 0.0,
+            });
+            range.length = frame.offset + frame.len as u64 - range.offset;
+            range.duration += frame.duration;
+            if range.duration >= target_secs {
+                segments.extend(current.take());
+            }
+        }
+        segments.extend(current);
+        segments
+    }
+}
+
+// Size of an ID3v2 tag whose header starts `data`, or 0.
+fn id3v2_len(data: &[u8]) -> u64 {
+    match data {
+        [b'I', b'D', b'3', _, _, flags, size @ ..] if size.len() >= 4 => {
+            // Sync-safe integer: 7 bits per byte
+            let len = size[..4]
+                .iter()
+                .fold(0u64, |len, b| (len << 7) | (*b & 0x7F) as u64);
+            let footer = if flags & 0x10 != 0 { 10 } else { 0 };
+            10 + len + footer
+        }
+        _ => 0,
+    }
+}
+
+// The part of a reader around the scan position. Bytes before the position
+// are dropped once there is a block of them.
+struct Window<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    // Offset of `buffer[0]` in the stream
+    start: u64,
+    eof: bool,
+}
+
+impl<R: Read> Window<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            start: 0,
+            eof: false,
+        }
+    }
+
+    // The bytes from offset `at` on: at least `len` of them unless the
+    // stream ends first. `at` never goes back.
+    fn get(&mut self, at: u64, len: usize) -> io::Result<&[u8]> {
+        loop {
+            let consumed = at.saturating_sub(self.start).min(self.buffer.len() as u64) as usize;
+            if consumed >= READ_SIZE {
+                self.buffer.drain(..consumed);
+                self.start += consumed as u64;
+            }
+            if self.eof || self.start + self.buffer.len() as u64 >= at + len as u64 {
+                break;
+            }
+            let n = (&mut self.reader)
+                .take(READ_SIZE as u64)
+                .read_to_end(&mut self.buffer)?;
+            self.eof = n == 0;
+        }
+        let from = at.saturating_sub(self.start).min(self.buffer.len() as u64) as usize;
+        Ok(&self.buffer[from..])
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    let bytes = data.get(at..at + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MPEG-1 Layer III, 128 kbit/s, 44.1 kHz, joint stereo: 417 bytes
+    const CBR_128: [u8; 4] = [0xFF, 0xFB, 0x90, 0x40];
+
+    fn frame(header: [u8; 4]) -> Vec<u8> {
+        let len = FrameHeader::parse(&header).unwrap().frame_len();
+        let mut frame = vec![0; len];
+        frame[..4].copy_from_slice(&header);
+        frame
+    }
+
+    fn frames(count: usize) -> Vec<u8> {
+        frame(CBR_128).repeat(count)
+    }
+
+    fn id3v2(body_len: usize) -> Vec<u8> {
+        let size = body_len as u32;
+        let mut tag = b"ID3\x04\x00\x00".to_vec();
+        tag.extend([21, 14, 7, 0].map(|shift| ((size >> shift) & 0x7F) as u8));
+        tag.resize(10 + body_len, 0);
+        tag
+    }
+
+    fn offsets(stream: &Mp3Stream) -> Vec<u64> {
+        stream.frames.iter().map(|frame| frame.offset).collect()
+    }
+
+    #[test]
+    fn parses_mpeg1_layer3_headers() {
+        let header = FrameHeader::parse(&CBR_128).unwrap();
+        assert_eq!(
+            header,
+            FrameHeader {
+                version: Version::Mpeg1,
+                layer: 3,
+                bitrate: 128_000,
+                sample_rate: 44100,
+                padding: false,
+                mono: false,
+            }
+        );
+        assert_eq!(header.samples(), 1152);
+        assert_eq!(header.frame_len(), 417);
+        assert!((header.duration() - 1152.0 / 44100.0).abs() < 1e-9);
+
+        let padded = FrameHeader::parse(&[0xFF, 0xFB, 0x92, 0x40]).unwrap();
+        assert!(padded.padding);
+        assert_eq!(padded.frame_len(), 418);
+
+        // 320 kbit/s at 48 kHz, mono
+        let header = FrameHeader::parse(&[0xFF, 0xFB, 0xE4, 0xC0]).unwrap();
+        assert_eq!(header.bitrate, 320_000);
+        assert_eq!(header.sample_rate, 48000);
+        assert!(header.mono);
+        assert_eq!(header.frame_len(), 960);
+    }
+
+    #[test]
+    fn parses_mpeg2_and_layer1_headers() {
+        // MPEG-2 Layer III, 64 kbit/s, 22.05 kHz, mono
+        let header = FrameHeader::parse(&[0xFF, 0xF3, 0x80, 0xC0]).unwrap();
+        assert_eq!(header.version, Version::Mpeg2);
+        assert_eq!(header.bitrate, 64_000);
+        assert_eq!(header.sample_rate, 22050);
+        assert_eq!(header.samples(), 576);
+        assert_eq!(header.frame_len(), 208);
+
+        // MPEG-2.5 Layer III, 8 kbit/s, 8 kHz
+        let header = FrameHeader::parse(&[0xFF, 0xE3, 0x18, 0x00]).unwrap();
+        assert_eq!(header.version, Version::Mpeg25);
+        assert_eq!(header.bitrate, 8_000);
+        assert_eq!(header.sample_rate, 8000);
+        assert_eq!(header.frame_len(), 72);
+
+        // MPEG-1 Layer I, 32 kbit/s, 48 kHz, padded with one 4 byte slot
+        let header = FrameHeader::parse(&[0xFF, 0xFF, 0x16, 0x00]).unwrap();
+        assert_eq!(header.layer, 1);
+        assert_eq!(header.samples(), 384);
+        assert_eq!(header.frame_len(), 36);
+
+        // MPEG-1 Layer II, 192 kbit/s, 48 kHz
+        let header = FrameHeader::parse(&[0xFF, 0xFD, 0xA4, 0x00]).unwrap();
+        assert_eq!(header.layer, 2);
+        assert_eq!(header.bitrate, 192_000);
+        assert_eq!(header.frame_len(), 576);
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        for bytes in [
+            &[0xFF, 0xFB, 0x90][..],
+            // No sync
+            &[0xFE, 0xFB, 0x90, 0x40],
+            &[0xFF, 0x1B, 0x90, 0x40],
+            // Reserved version and layer
+            &[0xFF, 0xEB, 0x90, 0x40],
+            &[0xFF, 0xF9, 0x90, 0x40],
+            // Free format and bad bitrate
+            &[0xFF, 0xFB, 0x00, 0x40],
+            &[0xFF, 0xFB, 0xF0, 0x40],
+            // Reserved sample rate
+            &[0xFF, 0xFB, 0x9C, 0x40],
+        ] {
+            assert_eq!(FrameHeader::parse(bytes), None, "{:02X?}", bytes);
+        }
+    }
+
+    #[test]
+    fn parses_xing_and_info_headers() {
+        let header = FrameHeader::parse(&CBR_128).unwrap();
+        let mut xing = frame(CBR_128);
+        xing[36..40].copy_from_slice(b"Xing");
+        xing[40..44].copy_from_slice(&3u32.to_be_bytes());
+        xing[44..48].copy_from_slice(&100u32.to_be_bytes());
+        xing[48..52].copy_from_slice(&41_700u32.to_be_bytes());
+        assert_eq!(
+            VbrHeader::parse(&header, &xing),
+            Some(VbrHeader {
+                kind: VbrKind::Xing,
+                frames: Some(100),
+                bytes: Some(41_700),
+            })
+        );
+
+        // Only the byte count, written right after the flags
+        let mut info = frame(CBR_128);
+        info[36..40].copy_from_slice(b"Info");
+        info[40..44].copy_from_slice(&2u32.to_be_bytes());
+        info[44..48].copy_from_slice(&41_700u32.to_be_bytes());
+        assert_eq!(
+            VbrHeader::parse(&header, &info),
+            Some(VbrHeader {
+                kind: VbrKind::Info,
+                frames: None,
+                bytes: Some(41_700),
+            })
+        );
+
+        // Mono MPEG-1 has shorter side information
+        let mono = FrameHeader::parse(&[0xFF, 0xFB, 0x90, 0xC0]).unwrap();
+        let mut tag = frame([0xFF, 0xFB, 0x90, 0xC0]);
+        tag[21..25].copy_from_slice(b"Xing");
+        assert_eq!(
+            VbrHeader::parse(&mono, &tag).map(|vbr| vbr.kind),
+            Some(VbrKind::Xing)
+        );
+        assert_eq!(VbrHeader::parse(&header, &tag), None);
+        assert_eq!(VbrHeader::parse(&header, &frame(CBR_128)), None);
+    }
+
+    #[test]
+    fn parses_vbri_headers() {
+        let header = FrameHeader::parse(&CBR_128).unwrap();
+        let mut vbri = frame(CBR_128);
+        vbri[36..40].copy_from_slice(b"VBRI");
+        vbri[46..50].copy_from_slice(&41_700u32.to_be_bytes());
+        vbri[50..54].copy_from_slice(&100u32.to_be_bytes());
+        assert_eq!(
+            VbrHeader::parse(&header, &vbri),
+            Some(VbrHeader {
+                kind: VbrKind::Vbri,
+                frames: Some(100),
+                bytes: Some(41_700),
+            })
+        );
+    }
+
+    #[test]
+    fn scans_frames_after_id3v2_and_vbr_headers() {
+        // The tag holds something that looks like a frame header
+        let mut tag = id3v2(100);
+        tag[20..24].copy_from_slice(&CBR_128);
+        let mut xing = frame(CBR_128);
+        xing[36..40].copy_from_slice(b"Info");
+        let data = [tag, xing, frames(3)].concat();
+
+        let stream = Mp3Stream::scan(&data).unwrap();
+        assert_eq!(stream.vbr.map(|vbr| vbr.kind), Some(VbrKind::Info));
+        assert_eq!(offsets(&stream), [527, 944, 1361]);
+        assert!(stream.frames.iter().all(|frame| frame.len == 417));
+        assert!((stream.duration() - 3.0 * 1152.0 / 44100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn resyncs_after_junk() {
+        // A lone sync word in the junk is not followed by a frame
+        let mut junk = b"junk".to_vec();
+        junk.extend([0xFF, 0xFB, 0x90, 0x40, 0x00]);
+        let data = [frames(2), junk, frames(2), b"TAG".to_vec()].concat();
+
+        let stream = Mp3Stream::scan(&data).unwrap();
+        assert_eq!(offsets(&stream), [0, 417, 843, 1260]);
+        assert_eq!(stream.vbr, None);
+    }
+
+    // Reader handing out a few bytes per call.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(7);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn reads_files_larger_than_the_window() {
+        // A tag and audio of several blocks each, with junk between frames
+        let mut tag = id3v2(3 * READ_SIZE);
+        tag[READ_SIZE..READ_SIZE + 4].copy_from_slice(&CBR_128);
+        let audio = [frames(200), b"junk".to_vec(), frames(200)].concat();
+        let data = [tag, audio].concat();
+
+        let stream = Mp3Stream::read(Trickle(&data)).unwrap().unwrap();
+        assert_eq!(stream.frames.len(), 400);
+        assert_eq!(stream.frames[0].offset, 10 + 3 * READ_SIZE as u64);
+        assert_eq!(offsets(&stream), offsets(&Mp3Stream::scan(&data).unwrap()));
+        let last = stream.frames.last().unwrap();
+        assert_eq!(last.offset + last.len as u64, data.len() as u64);
+    }
+
+    #[test]
+    fn no_frame_is_longer_than_the_window_looks_ahead() {
+        let mut longest = 0;
+        for b1 in 0xE0..=0xFF {
+            for b2 in 0..=0xFF {
+                if let Some(header) = FrameHeader::parse(&[0xFF, b1, b2, 0]) {
+                    longest = longest.max(header.frame_len());
+                }
+            }
+        }
+        assert_eq!(longest, MAX_FRAME_LEN);
+    }
+
+    #[test]
+    fn finds_no_audio_in_junk() {
+        assert!(Mp3Stream::scan(b"").is_none());
+        assert!(Mp3Stream::scan(&id3v2(50)).is_none());
+        assert!(Mp3Stream::scan(&[0xFF, 0xFB, 0x90, 0x40, 0x00]).is_none());
+    }
+
+    #[test]
+    fn segments_end_on_the_first_frame_past_the_target() {
+        let stream = Mp3Stream::scan(&frames(10)).unwrap();
+        let segments = stream.segments(0.1);
+        // Four 26 ms frames reach 0.1 s, the remainder forms the last run
+        assert_eq!(
+            segments
+                .iter()
+                .map(|range| (range.offset, range.length))
+                .collect::<Vec<_>>(),
+            [(0, 1668), (1668, 1668), (3336, 834)]
+        );
+        let duration: f64 = segments.iter().map(|range| range.duration).sum();
+        assert!((duration - stream.duration()).abs() < 1e-9);
+
+        // One run when the target is longer than the stream, one per frame
+        // when it is shorter than a frame
+        assert_eq!(stream.segments(10.0).len(), 1);
+        assert_eq!(stream.segments(0.001).len(), 10);
+        assert!(Mp3Stream::default().segments(1.0).is_empty());
+    }
+
+    #[test]
+    fn segments_keep_junk_between_frames() {
+        let data = [frames(2), b"junk".to_vec(), frames(3)].concat();
+        let stream = Mp3Stream::scan(&data).unwrap();
+        let segments = stream.segments(0.07);
+        assert_eq!(
+            segments
+                .iter()
+                .map(|range| (range.offset, range.length))
+                .collect::<Vec<_>>(),
+            [(0, 1255), (1255, 834)]
+        );
+
+        // Junk right after a run ends is left out of both
+        let data = [frames(2), b"junk".to_vec(), frames(2)].concat();
+        let stream = Mp3Stream::scan(&data).unwrap();
+        let segments = stream.segments(0.05);
+        assert_eq!(
+            segments
+                .iter()
+                .map(|range| (range.offset, range.length))
+                .collect::<Vec<_>>(),
+            [(0, 834), (838, 834)]
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use std::process::{ExitStatus, Output, Stdio};
+
+use anyhow::Context;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+// Lines of stderr kept to classify a failed tool run.
+const STDERR_TAIL: usize = 20;
+
+/// Receives every line a tool prints, for the job log and progress parsing.
+pub type ToolOutput = mpsc::UnboundedSender<String>;
+
+pub struct ToolRun {
+    pub status: ExitStatus,
+    pub stderr_tail: Vec<String>,
+}
+
+/// Runs a tool to completion, sending everything it prints to `output`.
+///
+/// The tool gets its own process group, killed as a whole when the returned
+/// future is dropped early, so helpers it spawns (yt-dlp runs ffmpeg) do not
+/// outlive a cancelled job.
+pub async fn run(mut command: Command, output: &ToolOutput) -> anyhow::Result<ToolRun> {
+    let program = command.as_std().get_program().to_string_lossy().to_string();
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to run {}", program))?;
+    let mut group = ProcessGroup(child.id());
+
+    let mut stdout = child.stdout.take().map(OutputLines::new);
+    let mut stderr = child.stderr.take().map(OutputLines::new);
+    let mut stderr_tail = Vec::new();
+    // Both pipes are drained so a chatty tool never blocks on a full buffer
+    loop {
+        let line = tokio::select! {
+            line = async { stdout.as_mut()?.next_line().await.ok()? }, if stdout.is_some() => {
+                if line.is_none() {
+                    stdout = None;
+                }
+                line
+            }
+            line = async { stderr.as_mut()?.next_line().await.ok()? }, if stderr.is_some() => {
+                if line.is_none() {
+                    stderr = None;
+                }
+                if let Some(line) = &line {
+                    if stderr_tail.len() == STDERR_TAIL {
+                        stderr_tail.remove(0);
+                    }
+                    stderr_tail.push(line.clone());
+                }
+                line
+            }
+            else => break,
+        };
+        if let Some(line) = line {
+            let _ = output.send(line);
+        }
+    }
+
+    let status = child.wait().await?;
+    group.0 = None;
+    Ok(ToolRun {
+        status,
+        stderr_tail,
+    })
+}
+
+/// Runs a tool to completion and collects what it printed, like
+/// `Command::output`, killing its process group when dropped early.
+pub async fn output(mut command: Command) -> anyhow::Result<Output> {
+    let program = command.as_std().get_program().to_string_lossy().to_string();
+    let child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to run {}", program))?;
+    let mut group = ProcessGroup(child.id());
+    let output = child.wait_with_output().await?;
+    group.0 = None;
+    Ok(output)
+}
+
+// Kills the process group of a tool that is still running when dropped.
+struct ProcessGroup(Option<u32>);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            // SAFETY: plain kill(2) on the process group created for the tool
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+            }
+        }
+    }
+}
+
+// Splits tool output on `\n` and on the `\r` yt-dlp and ffmpeg use to redraw
+// their progress line, skipping empty lines.
+struct OutputLines<R> {
+    reader: BufReader<R>,
+    line: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> OutputLines<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            reader: BufReader::new(inner),
+            line: Vec::new(),
+        }
+    }
+
+    async fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        loop {
+            let buf = self.reader.fill_buf().await?;
+            if buf.is_empty() {
+                if self.line.is_empty() {
+                    return Ok(None);
+                }
+                return Ok(Some(self.take_line()));
+            }
+            match buf.iter().position(|b| *b == b'\n' || *b == b'\r') {
+                Some(end) => {
+                    self.line.extend_from_slice(&buf[..end]);
+                    self.reader.consume(end + 1);
+                    if !self.line.is_empty() {
+                        return Ok(Some(self.take_line()));
+                    }
+                }
+                None => {
+                    let len = buf.len();
+                    self.line.extend_from_slice(buf);
+                    self.reader.consume(len);
+                }
+            }
+        }
+    }
+
+    fn take_line(&mut self) -> String {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        line
+    }
+}
@@ -0,0 +1,284 @@
+use serde::{Deserialize, Serialize};
+
+/// Share of the overall percentage taken by the download; converting takes the rest.
+pub const DOWNLOAD_WEIGHT: f32 = 0.6;
+
+/// Parsed yt-dlp `[download]` progress line.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DownloadProgress {
+    pub percent: f32,
+    /// As printed by yt-dlp, e.g. `1.02MiB/s`.
+    pub speed: Option<String>,
+    /// As printed by yt-dlp, e.g. `00:03`.
+    pub eta: Option<String>,
+}
+
+/// A progress reading from one of the pipeline tools.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressUpdate {
+    Download(DownloadProgress),
+    Convert { percent: f32 },
+}
+
+impl ProgressUpdate {
+    /// Weighted percentage of the whole job.
+    pub fn overall(&self) -> u8 {
+        let overall = match self {
+            ProgressUpdate::Download(download) => download.percent * DOWNLOAD_WEIGHT,
+            ProgressUpdate::Convert { percent } => {
+                DOWNLOAD_WEIGHT * 100.0 + percent * (1.0 - DOWNLOAD_WEIGHT)
+            }
+        };
+        overall.clamp(0.0, 100.0) as u8
+    }
+
+    fn stage_percent(&self) -> f32 {
+        match self {
+            ProgressUpdate::Download(download) => download.percent,
+            ProgressUpdate::Convert { percent } => *percent,
+        }
+    }
+}
+
+/// Turns the output of one pipeline stage into progress updates.
+pub enum StageProgress {
+    Download,
+    /// `duration` is the track length in seconds when known up front; ffmpeg's
+    /// own `Duration:` line replaces it.
+    Convert {
+        duration: Option<f64>,
+    },
+}
+
+impl StageProgress {
+    /// Feeds one line of tool output. Returns an update for progress lines and
+    /// `None` for everything else.
+    pub fn parse(&mut self, line: &str) -> Option<ProgressUpdate> {
+        match self {
+            StageProgress::Download => parse_download(line).map(ProgressUpdate::Download),
+            StageProgress::Convert { duration } => {
+                if let Some(total) = parse_ffmpeg_duration(line) {
+                    *duration = Some(total);
+                    return None;
+                }
+                let time = parse_ffmpeg_time(line)?;
+                let total = duration.filter(|total| *total > 0.0)?;
+                Some(ProgressUpdate::Convert {
+                    percent: ((time / total) * 100.0).clamp(0.0, 100.0) as f32,
+                })
+            }
+        }
+    }
+}
+
+/// Whether `update` is worth recording after `last`: only whole-percent steps
+/// of the stage count, so the job row is not rewritten for every output line.
+pub fn changed(last: Option<&ProgressUpdate>, update: &ProgressUpdate) -> bool {
+    match last {
+        Some(last) => {
+            std::mem::discriminant(last) != std::mem::discriminant(update)
+                || last.stage_percent().floor() != update.stage_percent().floor()
+        }
+        None => true,
+    }
+}
+
+/// `[download]  45.3% of ~  3.21MiB at  1.02MiB/s ETA 00:03 (frag 2/9)` or
+/// `[download] 100% of 3.21MiB in 00:00:02 at 1.20MiB/s`.
+pub fn parse_download(line: &str) -> Option<DownloadProgress> {
+    let rest = line.trim_start().strip_prefix("[download]")?;
+    let mut words = rest.split_whitespace();
+    let percent = words.next()?.strip_suffix('%')?.parse::<f32>().ok()?;
+
+    let mut progress = DownloadProgress {
+        percent,
+        ..Default::default()
+    };
+    while let Some(word) = words.next() {
+        match word {
+            "at" => progress.speed = words.next().filter(|s| *s != "Unknown").map(String::from),
+            "ETA" => progress.eta = words.next().filter(|s| *s != "Unknown").map(String::from),
+            _ => {}
+        }
+    }
+    Some(progress)
+}
+
+/// Seconds from ffmpeg's `Duration: 00:03:21.05, start: ...` input line.
+pub fn parse_ffmpeg_duration(line: &str) -> Option<f64> {
+    let value = line.trim_start().strip_prefix("Duration:")?;
+    parse_clock(value.split(',').next()?.trim())
+}
+
+/// Seconds from the `time=00:00:05.00` field of an ffmpeg stats line.
+pub fn parse_ffmpeg_time(line: &str) -> Option<f64> {
+    let start = line.find("time=")? + "time=".len();
+    parse_clock(line[start..].split_whitespace().next()?)
+}
+
+/// Seconds in `hh:mm:ss(.ff)`, `mm:ss` or plain seconds, as printed by ffmpeg
+/// and in yt-dlp's `duration_string`.
+pub fn parse_clock(value: &str) -> Option<f64> {
+    if value.is_empty() || value.starts_with('-') {
+        return None;
+    }
+    value.split(':').try_fold(0.0, |total, part| {
+        let part: f64 = part.parse().ok()?;
+        part.is_finite().then_some(total * 60.0 + part)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download(percent: f32, speed: Option<&str>, eta: Option<&str>) -> DownloadProgress {
+        DownloadProgress {
+            percent,
+            speed: speed.map(String::from),
+            eta: eta.map(String::from),
+        }
+    }
+
+    #[test]
+    fn parses_yt_dlp_progress_lines() {
+        assert_eq!(
+            parse_download("[download]  45.3% of    3.21MiB at    1.02MiB/s ETA 00:03"),
+            Some(download(45.3, Some("1.02MiB/s"), Some("00:03")))
+        );
+        assert_eq!(
+            parse_download("[download] 100% of    3.21MiB in 00:00:02 at 1.20MiB/s"),
+            Some(download(100.0, Some("1.20MiB/s"), None))
+        );
+    }
+
+    #[test]
+    fn parses_estimated_sizes_and_fragments() {
+        assert_eq!(
+            parse_download(
+                "[download]  23.5% of ~  12.34MiB at  512.00KiB/s ETA 00:07 (frag 5/21)"
+            ),
+            Some(download(23.5, Some("512.00KiB/s"), Some("00:07")))
+        );
+        assert_eq!(
+            parse_download(
+                "[download] 100.0% of ~  12.34MiB at    2.10MiB/s ETA 00:00 (frag 21/21)"
+            ),
+            Some(download(100.0, Some("2.10MiB/s"), Some("00:00")))
+        );
+    }
+
+    #[test]
+    fn drops_unknown_speed_and_eta() {
+        assert_eq!(
+            parse_download("[download]   0.0% of    3.21MiB at  Unknown B/s ETA Unknown"),
+            Some(download(0.0, None, None))
+        );
+        assert_eq!(
+            parse_download(
+                "[download]   5.1% of ~   3.21MiB at  Unknown B/s ETA Unknown (frag 0/9)"
+            ),
+            Some(download(5.1, None, None))
+        );
+    }
+
+    #[test]
+    fn ignores_other_yt_dlp_output() {
+        for line in [
+            "[youtube] Extracting URL: https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "[download] Destination: staging/1b2c/1b2c.webm",
+            "[download] staging/1b2c/1b2c.mp3 has already been downloaded",
+            "[ExtractAudio] Destination: staging/1b2c/1b2c.mp3",
+            "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable",
+            "",
+        ] {
+            assert_eq!(parse_download(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn parses_ffmpeg_duration_lines() {
+        assert_eq!(
+            parse_ffmpeg_duration("  Duration: 00:03:21.05, start: 0.025057, bitrate: 128 kb/s"),
+            Some(201.05)
+        );
+        assert_eq!(
+            parse_ffmpeg_duration("  Duration: N/A, start: 0.000000, bitrate: N/A"),
+            None
+        );
+        assert_eq!(
+            parse_ffmpeg_duration("    Stream #0:0: Audio: mp3, 44100 Hz, stereo, fltp, 128 kb/s"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_ffmpeg_time_fields() {
+        assert_eq!(
+            parse_ffmpeg_time(
+                "size=     256kB time=00:00:16.32 bitrate= 128.5kbits/s speed=32.6x    "
+            ),
+            Some(16.32)
+        );
+        assert_eq!(
+            parse_ffmpeg_time("size=N/A time=00:01:05.00 bitrate=N/A speed=10.1x"),
+            Some(65.0)
+        );
+        assert_eq!(
+            parse_ffmpeg_time("size=N/A time=N/A bitrate=N/A speed=N/A"),
+            None
+        );
+        assert_eq!(
+            parse_ffmpeg_time("size=       0kB time=-00:00:00.02 bitrate=N/A speed=N/A"),
+            None
+        );
+        assert_eq!(
+            parse_ffmpeg_time("[hls @ 0x55d5] Opening 'out/64k_segment_000.ts' for writing"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_clocks() {
+        assert_eq!(parse_clock("3:21"), Some(201.0));
+        assert_eq!(parse_clock("1:02:03.5"), Some(3723.5));
+        assert_eq!(parse_clock("42"), Some(42.0));
+        assert_eq!(parse_clock(""), None);
+        assert_eq!(parse_clock("N/A"), None);
+        assert_eq!(parse_clock("1::2"), None);
+    }
+
+    #[test]
+    fn convert_stage_uses_ffmpeg_duration() {
+        let mut stage = StageProgress::Convert { duration: None };
+        // No duration yet
+        assert_eq!(stage.parse("size=N/A time=00:00:05.00 bitrate=N/A"), None);
+        assert_eq!(
+            stage.parse("  Duration: 00:00:20.00, start: 0.000000, bitrate: 128 kb/s"),
+            None
+        );
+        assert_eq!(
+            stage.parse("size=N/A time=00:00:05.00 bitrate=N/A"),
+            Some(ProgressUpdate::Convert { percent: 25.0 })
+        );
+        assert_eq!(
+            stage.parse("size=N/A time=00:00:30.00 bitrate=N/A"),
+            Some(ProgressUpdate::Convert { percent: 100.0 })
+        );
+        assert_eq!(stage.parse("size=N/A time=N/A bitrate=N/A"), None);
+    }
+
+    #[test]
+    fn weighs_stages_into_the_overall_percentage() {
+        let half = ProgressUpdate::Download(download(50.0, None, None));
+        assert_eq!(half.overall(), 30);
+        assert_eq!(ProgressUpdate::Convert { percent: 50.0 }.overall(), 80);
+        assert!(changed(None, &half));
+        let same = ProgressUpdate::Download(download(50.9, None, None));
+        assert!(!changed(Some(&half), &same));
+        assert!(changed(
+            Some(&half),
+            &ProgressUpdate::Convert { percent: 50.0 }
+        ));
+    }
+}
@@ -0,0 +1,297 @@
+use chrono::{DateTime, Utc};
+
+use crate::http::parse_http_date;
+
+/// More ranges than this in one request are ignored and the full body is sent.
+pub const MAX_RANGES: usize = 16;
+
+/// An inclusive byte range `start..=end` of a representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// What a `Range` header asks for once evaluated against the representation size.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable `Range` header; the full representation is sent with `200`.
+    Full,
+    /// One or more satisfiable ranges, already clamped to the representation.
+    Partial(Vec<ByteRange>),
+    /// A valid header none of whose ranges overlap the representation (`416`).
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// `Content-Range` value for this range of a `size`-byte representation.
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+
+    /// Evaluates a `Range` header (RFC 7233 section 2.1) against a representation
+    /// of `size` bytes. Supports `start-end`, open-ended `start-` and suffix `-n`
+    /// specs in a comma-separated list; a header that does not parse, uses a unit
+    /// other than `bytes` or lists too many ranges is ignored.
+    pub fn parse(header: &str, size: u64) -> RangeRequest {
+        let Some((unit, specs)) = header.split_once('=') else {
+            return RangeRequest::Full;
+        };
+        if !unit.trim().eq_ignore_ascii_case("bytes") {
+            return RangeRequest::Full;
+        }
+
+        let mut ranges = Vec::new();
+        let mut count = 0;
+        for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            count += 1;
+            if count > MAX_RANGES {
+                return RangeRequest::Full;
+            }
+            let Some((first, last)) = spec.split_once('-') else {
+                return RangeRequest::Full;
+            };
+            let (first, last) = (first.trim(), last.trim());
+            let range = match (first.is_empty(), last.is_empty()) {
+                // Suffix: the final `last` bytes
+                (true, false) => {
+                    let Some(suffix) = parse_digits(last) else {
+                        return RangeRequest::Full;
+                    };
+                    (suffix > 0 && size > 0).then(|| ByteRange {
+                        start: size.saturating_sub(suffix),
+                        end: size - 1,
+                    })
+                }
+                (false, _) => {
+                    let Some(start) = parse_digits(first) else {
+                        return RangeRequest::Full;
+                    };
+                    let end = if last.is_empty() {
+                        u64::MAX
+                    } else {
+                        match parse_digits(last) {
+                            Some(end) if end >= start => end,
+                            _ => return RangeRequest::Full,
+                        }
+                    };
+                    (start < size).then(|| ByteRange {
+                        start,
+                        end: end.min(size - 1),
+                    })
+                }
+                (true, true) => return RangeRequest::Full,
+            };
+            ranges.extend(range);
+        }
+
+        if count == 0 {
+            RangeRequest::Full
+        } else if ranges.is_empty() {
+            RangeRequest::Unsatisfiable
+        } else {
+            RangeRequest::Partial(ranges)
+        }
+    }
+}
+
+/// Whether an `If-Range` value still matches the current representation, so the
+/// `Range` header may be honoured. Entity tags must match strongly; a date must
+/// equal `Last-Modified` exactly.
+pub fn if_range_matches(
+    if_range: &str,
+    etag: Option<&str>,
+    last_modified: Option<DateTime<Utc>>,
+) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with("W/") {
+        return false;
+    }
+    if if_range.starts_with('"') {
+        return etag == Some(if_range);
+    }
+    match (parse_http_date(if_range), last_modified) {
+        (Some(date), Some(modified)) => date.timestamp() == modified.timestamp(),
+        _ => false,
+    }
+}
+
+// Digits only, as `u64::from_str` would also accept a leading `+`. Values too
+// large for a u64 saturate, which keeps them past the end of any file.
+fn parse_digits(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(s.parse().unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(
+            ranges
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parses_closed_ranges() {
+        assert_eq!(ByteRange::parse("bytes=0-499", 1000), partial(&[(0, 499)]));
+        assert_eq!(ByteRange::parse("bytes=0-0", 1000), partial(&[(0, 0)]));
+        // The end is clamped to the representation
+        assert_eq!(
+            ByteRange::parse("bytes=500-5000", 1000),
+            partial(&[(500, 999)])
+        );
+        assert_eq!(
+            ByteRange::parse(" BYTES = 1 - 2 ", 1000),
+            partial(&[(1, 2)])
+        );
+    }
+
+    #[test]
+    fn parses_open_ended_ranges() {
+        assert_eq!(ByteRange::parse("bytes=900-", 1000), partial(&[(900, 999)]));
+        assert_eq!(ByteRange::parse("bytes=0-", 1), partial(&[(0, 0)]));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(ByteRange::parse("bytes=-100", 1000), partial(&[(900, 999)]));
+        // A suffix longer than the representation covers all of it
+        assert_eq!(ByteRange::parse("bytes=-5000", 1000), partial(&[(0, 999)]));
+        assert_eq!(
+            ByteRange::parse("bytes=-0", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-10", 0),
+            RangeRequest::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn keeps_multiple_and_overlapping_ranges_in_order() {
+        assert_eq!(
+            ByteRange::parse("bytes=0-99, 200-299, -50", 1000),
+            partial(&[(0, 99), (200, 299), (950, 999)])
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=0-499,100-199,400-", 1000),
+            partial(&[(0, 499), (100, 199), (400, 999)])
+        );
+        // Unsatisfiable specs are dropped while the rest is served
+        assert_eq!(
+            ByteRange::parse("bytes=5000-6000,0-9", 1000),
+            partial(&[(0, 9)])
+        );
+    }
+
+    #[test]
+    fn caps_the_number_of_ranges() {
+        let specs = |n: u64| {
+            (0..n)
+                .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let RangeRequest::Partial(ranges) =
+            ByteRange::parse(&format!("bytes={}", specs(MAX_RANGES as u64)), 1000)
+        else {
+            panic!("expected a partial request");
+        };
+        assert_eq!(ranges.len(), MAX_RANGES);
+        assert_eq!(
+            ByteRange::parse(&format!("bytes={}", specs(MAX_RANGES as u64 + 1)), 1000),
+            RangeRequest::Full
+        );
+    }
+
+    #[test]
+    fn reports_unsatisfiable_ranges() {
+        assert_eq!(
+            ByteRange::parse("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=1000-2000,3000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(ByteRange::parse("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(
+            ByteRange::parse("bytes=99999999999999999999999-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn ignores_malformed_headers() {
+        for header in [
+            "",
+            "bytes",
+            "bytes=",
+            "bytes=,,",
+            "items=0-10",
+            "bits=0-10",
+            "bytes=-",
+            "bytes=abc-def",
+            "bytes=+1-2",
+            "bytes=10-5",
+            "bytes=0-10,oops",
+            "bytes=0-10;1-2",
+        ] {
+            assert_eq!(
+                ByteRange::parse(header, 1000),
+                RangeRequest::Full,
+                "{}",
+                header
+            );
+        }
+    }
+
+    #[test]
+    fn content_range_and_length() {
+        let range = ByteRange { start: 10, end: 19 };
+        assert_eq!(range.length(), 10);
+        assert_eq!(range.content_range(100), "bytes 10-19/100");
+    }
+
+    #[test]
+    fn if_range_with_an_entity_tag_matches_strongly() {
+        let etag = Some("\"abc-1\"");
+        assert!(if_range_matches("\"abc-1\"", etag, None));
+        assert!(if_range_matches(" \"abc-1\" ", etag, None));
+        assert!(!if_range_matches("\"abc-2\"", etag, None));
+        assert!(!if_range_matches("W/\"abc-1\"", etag, None));
+        assert!(!if_range_matches("\"abc-1\"", None, None));
+    }
+
+    #[test]
+    fn if_range_with_a_date_matches_last_modified_exactly() {
+        let modified = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert!(if_range_matches(date, Some("\"abc\""), Some(modified)));
+        assert!(!if_range_matches(
+            "Sun, 06 Nov 1994 08:49:38 GMT",
+            None,
+            Some(modified)
+        ));
+        assert!(!if_range_matches(
+            "Sat, 05 Nov 1994 08:49:37 GMT",
+            None,
+            Some(modified)
+        ));
+        assert!(!if_range_matches(date, None, None));
+        assert!(!if_range_matches("yesterday", None, Some(modified)));
+    }
+}
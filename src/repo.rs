@@ -1,38 +1,438 @@
+use anyhow::Context;
 use sqlx::{Pool, Postgres};
 
-use crate::model::{GetTrack, Track};
+use uuid::Uuid;
+
+use crate::clip::Clip;
+use crate::model::{AddStream, GetTrack, Job, JobStatus, TaskStatus, Track};
+use crate::progress::ProgressUpdate;
 
 pub struct Repository {}
 
 impl Repository {
+    /// Stores a new track. Returns `None` when a track of the same source
+    /// video and clip already exists.
     pub async fn insert_track(
         new_track: &Track,
         pool: &Pool<Postgres>,
-    ) -> Result<i32, anyhow::Error> {
-        let row: (i32,) = match sqlx::query_as(
+    ) -> Result<Option<i32>, anyhow::Error> {
+        let row: Option<(i32,)> = sqlx::query_as(
             r#"
-            INSERT INTO tracks (title, duration, created_at) 
-            VALUES ($1, $2, $3) 
+            INSERT INTO tracks (title, duration, mp3_path, hls_playlist, created_at,
+                source_url, video_id, clip_start, clip_end, segment_format)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            ON CONFLICT (video_id, COALESCE(clip_start, -1), COALESCE(clip_end, -1))
+                WHERE video_id IS NOT NULL DO NOTHING
             RETURNING track_id"#,
         )
         .bind(&new_track.title)
         .bind(&new_track.duration)
-        .bind(&new_track.created_at)
-        .fetch_one(pool)
+        .bind(&new_track.mp3_path)
+        .bind(&new_track.hls_playlist)
+        .bind(new_track.created_at)
+        .bind(&new_track.source_url)
+        .bind(&new_track.video_id)
+        .bind(new_track.clip_start)
+        .bind(new_track.clip_end)
+        .bind(new_track.segment_format)
+        .fetch_optional(pool)
         .await
-        {
-            Ok(row) => row,
-            Err(e) => {
-                anyhow::bail!("failed insert {}", e)
-            }
-        };
-        Ok(row.0)
+        .context("failed insert")?;
+        Ok(row.map(|(track_id,)| track_id))
     }
 
-    pub async fn fetch_all_tracks(pool: &Pool<Postgres>) -> Result<Vec<GetTrack>, anyhow::Error> {
-        let tracks = sqlx::query_as::<_, GetTrack>(r#"SELECT title, duration FROM tracks"#)
-            .fetch_all(pool)
+    /// Removes a track whose files could not be put in place.
+    pub async fn delete_track(track_id: i32, pool: &Pool<Postgres>) -> Result<(), anyhow::Error> {
+        sqlx::query("DELETE FROM tracks WHERE track_id = $1")
+            .bind(track_id)
+            .execute(pool)
             .await?;
+        Ok(())
+    }
+
+    pub async fn fetch_all_tracks(pool: &Pool<Postgres>) -> Result<Vec<GetTrack>, anyhow::Error> {
+        let tracks = sqlx::query_as::<_, GetTrack>(
+            r#"SELECT track_id, title, duration FROM tracks ORDER BY track_id"#,
+        )
+        .fetch_all(pool)
+        .await?;
         Ok(tracks)
     }
+
+    pub async fn fetch_track(
+        track_id: i32,
+        pool: &Pool<Postgres>,
+    ) -> Result<Option<Track>, anyhow::Error> {
+        let track = sqlx::query_as::<_, Track>(
+            r#"
+            SELECT track_id, title, duration, mp3_path, hls_playlist, created_at,
+                source_url, video_id, clip_start, clip_end, segment_format
+            FROM tracks WHERE track_id = $1"#,
+        )
+        .bind(track_id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(track)
+    }
+
+    pub async fn fetch_track_by_title(
+        title: &str,
+        pool: &Pool<Postgres>,
+    ) -> Result<Option<Track>, anyhow::Error> {
+        let track = sqlx::query_as::<_, Track>(
+            r#"
+            SELECT track_id, title, duration, mp3_path, hls_playlist, created_at,
+                source_url, video_id, clip_start, clip_end, segment_format
+            FROM tracks WHERE title = $1 ORDER BY track_id LIMIT 1"#,
+        )
+        .bind(title)
+        .fetch_optional(pool)
+        .await?;
+        Ok(track)
+    }
+
+    /// Track cut to `clip` from `source_url` or from the same source video.
+    pub async fn fetch_track_by_source(
+        source_url: &str,
+        video_id: Option<&str>,
+        clip: Clip,
+        pool: &Pool<Postgres>,
+    ) -> Result<Option<Track>, anyhow::Error> {
+        let track = sqlx::query_as::<_, Track>(
+            r#"
+            SELECT track_id, title, duration, mp3_path, hls_playlist, created_at,
+                source_url, video_id, clip_start, clip_end, segment_format
+            FROM tracks
+            WHERE (source_url = $1 OR video_id = $2)
+                AND clip_start IS NOT DISTINCT FROM $3 AND clip_end IS NOT DISTINCT FROM $4
+            ORDER BY track_id LIMIT 1"#,
+        )
+        .bind(source_url)
+        .bind(video_id)
+        .bind(clip_column(clip.start)?)
+        .bind(clip_column(clip.end)?)
+        .fetch_optional(pool)
+        .await?;
+        Ok(track)
+    }
+
+    /// Queues a new job. Returns false when a job for the same source and
+    /// clip is already queued or running.
+    pub async fn insert_job(
+        job_id: Uuid,
+        body: &AddStream,
+        video_id: Option<&str>,
+        pool: &Pool<Postgres>,
+    ) -> Result<bool, anyhow::Error> {
+        let row: Option<(Uuid,)> = sqlx::query_as(
+            r#"
+            INSERT INTO jobs (job_id, title, youtube_url, clip_start, clip_end, video_id)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT DO NOTHING
+            RETURNING job_id"#,
+        )
+        .bind(job_id)
+        .bind(&body.title)
+        .bind(&body.youtube_url)
+        .bind(clip_column(body.start.map(|s| s.0))?)
+        .bind(clip_column(body.end.map(|e| e.0))?)
+        .bind(video_id)
+        .fetch_optional(pool)
+        .await
+        .context("failed insert job")?;
+        Ok(row.is_some())
+    }
+
+    /// Queued or running job for the same source and clip.
+    pub async fn fetch_active_job_by_source(
+        source_url: &str,
+        video_id: Option<&str>,
+        clip: Clip,
+        pool: &Pool<Postgres>,
+    ) -> Result<Option<Uuid>, anyhow::Error> {
+        let row: Option<(Uuid,)> = sqlx::query_as(
+            r#"
+            SELECT job_id FROM jobs
+            WHERE (youtube_url = $1 OR video_id = $2)
+                AND clip_start IS NOT DISTINCT FROM $3 AND clip_end IS NOT DISTINCT FROM $4
+                AND status IN ('queued', 'downloading', 'converting')
+            ORDER BY created_at LIMIT 1"#,
+        )
+        .bind(source_url)
+        .bind(video_id)
+        .bind(clip_column(clip.start)?)
+        .bind(clip_column(clip.end)?)
+        .fetch_optional(pool)
+        .await?;
+        Ok(row.map(|(job_id,)| job_id))
+    }
+
+    /// Records the source video of a job once the extractor reported it. It
+    /// stays unset when another active job has the same video and clip under
+    /// a different URL; inserting the track decides which of them is kept.
+    pub async fn set_job_video_id(
+        job_id: Uuid,
+        video_id: &str,
+        pool: &Pool<Postgres>,
+    ) -> Result<(), anyhow::Error> {
+        let result = sqlx::query("UPDATE jobs SET video_id = $2 WHERE job_id = $1")
+            .bind(job_id)
+            .bind(video_id)
+            .execute(pool)
+            .await;
+        match result {
+            Err(sqlx::Error::Database(db)) if db.is_unique_violation() => Ok(()),
+            result => {
+                result?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Takes the oldest queued job, skipping rows other workers are claiming.
+    pub async fn claim_job(pool: &Pool<Postgres>) -> Result<Option<Job>, anyhow::Error> {
+        let job = sqlx::query_as::<_, Job>(
+            r#"
+            UPDATE jobs SET status = 'downloading', progress = 0, error = NULL,
+                download_percent = NULL, download_speed = NULL,
+                download_eta = NULL, convert_percent = NULL,
+                attempts = attempts + 1, updated_at = CURRENT_TIMESTAMP
+            WHERE job_id = (
+                SELECT job_id FROM jobs
+                WHERE status = 'queued' AND run_after <= CURRENT_TIMESTAMP
+                ORDER BY run_after, created_at
+                FOR UPDATE SKIP LOCKED
+                LIMIT 1
+            )
+            RETURNING job_id, title, youtube_url, clip_start, clip_end, attempts"#,
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(job)
+    }
+
+    /// Puts jobs that were running when the server stopped back in the queue.
+    pub async fn requeue_interrupted_jobs(pool: &Pool<Postgres>) -> Result<u64, anyhow::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE jobs SET status = 'queued', updated_at = CURRENT_TIMESTAMP
+            WHERE status IN ('downloading', 'converting')"#,
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn update_job_status(
+        job_id: Uuid,
+        status: JobStatus,
+        progress: u8,
+        pool: &Pool<Postgres>,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query(
+            r#"
+            UPDATE jobs SET status = $2, progress = $3, updated_at = CURRENT_TIMESTAMP
+            WHERE job_id = $1 AND status IN ('downloading', 'converting')"#,
+        )
+        .bind(job_id)
+        .bind(status.as_str())
+        .bind(progress as i16)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Records a progress reading of the running stage.
+    pub async fn update_job_progress(
+        job_id: Uuid,
+        update: &ProgressUpdate,
+        pool: &Pool<Postgres>,
+    ) -> Result<(), anyhow::Error> {
+        let query = match update {
+            ProgressUpdate::Download(download) => sqlx::query(
+                r#"
+                UPDATE jobs SET progress = $2, download_percent = $3, download_speed = $4,
+                    download_eta = $5, updated_at = CURRENT_TIMESTAMP
+                WHERE job_id = $1 AND status = 'downloading'"#,
+            )
+            .bind(job_id)
+            .bind(update.overall() as i16)
+            .bind(download.percent)
+            .bind(&download.speed)
+            .bind(&download.eta),
+            ProgressUpdate::Convert { percent } => sqlx::query(
+                r#"
+                UPDATE jobs SET progress = $2, convert_percent = $3,
+                    updated_at = CURRENT_TIMESTAMP
+                WHERE job_id = $1 AND status = 'converting'"#,
+            )
+            .bind(job_id)
+            .bind(update.overall() as i16)
+            .bind(*percent),
+        };
+        query.execute(pool).await?;
+        Ok(())
+    }
+
+    /// Appends a line to the job log, keeping only the most recent 200 entries.
+    pub async fn append_job_log(
+        job_id: Uuid,
+        line: &str,
+        pool: &Pool<Postgres>,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query(
+            r#"
+            UPDATE jobs SET log = (array_append(log, $2))[greatest(cardinality(log) - 198, 1):]
+            WHERE job_id = $1"#,
+        )
+        .bind(job_id)
+        .bind(line)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Marks a running job as done. Returns false when it is no longer
+    /// running, e.g. because it was cancelled meanwhile.
+    pub async fn complete_job(
+        job_id: Uuid,
+        track_id: i32,
+        pool: &Pool<Postgres>,
+    ) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE jobs SET status = 'done', progress = 100, convert_percent = 100,
+                track_id = $2, updated_at = CURRENT_TIMESTAMP
+            WHERE job_id = $1 AND status IN ('downloading', 'converting')"#,
+        )
+        .bind(job_id)
+        .bind(track_id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn fail_job(
+        job_id: Uuid,
+        reason: &str,
+        pool: &Pool<Postgres>,
+    ) -> Result<(), anyhow::Error> {
+        Self::append_job_log(job_id, reason, pool).await?;
+        sqlx::query(
+            r#"
+            UPDATE jobs SET status = 'failed', error = $2, updated_at = CURRENT_TIMESTAMP
+            WHERE job_id = $1 AND status IN ('downloading', 'converting')"#,
+        )
+        .bind(job_id)
+        .bind(reason)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Puts a running job back in the queue, claimable after `delay_secs`.
+    pub async fn schedule_retry(
+        job_id: Uuid,
+        delay_secs: u64,
+        reason: &str,
+        pool: &Pool<Postgres>,
+    ) -> Result<(), anyhow::Error> {
+        Self::append_job_log(job_id, reason, pool).await?;
+        sqlx::query(
+            r#"
+            UPDATE jobs SET status = 'queued', progress = 0,
+                download_percent = NULL, download_speed = NULL,
+                download_eta = NULL, convert_percent = NULL,
+                run_after = CURRENT_TIMESTAMP + make_interval(secs => $2),
+                updated_at = CURRENT_TIMESTAMP
+            WHERE job_id = $1 AND status IN ('downloading', 'converting')"#,
+        )
+        .bind(job_id)
+        .bind(delay_secs as f64)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Marks a queued or running job as cancelled. Returns false when the job
+    /// does not exist or has already finished.
+    pub async fn cancel_job(job_id: Uuid, pool: &Pool<Postgres>) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE jobs SET status = 'cancelled', updated_at = CURRENT_TIMESTAMP
+            WHERE job_id = $1 AND status IN ('queued', 'downloading', 'converting')"#,
+        )
+        .bind(job_id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Requeues a failed or cancelled job with a fresh set of attempts.
+    pub async fn retry_job(job_id: Uuid, pool: &Pool<Postgres>) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE jobs SET status = 'queued', progress = 0, attempts = 0, error = NULL,
+                download_percent = NULL, download_speed = NULL,
+                download_eta = NULL, convert_percent = NULL,
+                run_after = CURRENT_TIMESTAMP, updated_at = CURRENT_TIMESTAMP
+            WHERE job_id = $1 AND status IN ('failed', 'cancelled')"#,
+        )
+        .bind(job_id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Removes a finished job; returns the status it had, if it was deleted.
+    pub async fn delete_job(
+        job_id: Uuid,
+        pool: &Pool<Postgres>,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let row: Option<(String,)> = sqlx::query_as(
+            r#"
+            DELETE FROM jobs
+            WHERE job_id = $1 AND status IN ('done', 'failed', 'cancelled')
+            RETURNING status"#,
+        )
+        .bind(job_id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(row.map(|(status,)| status))
+    }
+
+    pub async fn fetch_task(
+        job_id: Uuid,
+        pool: &Pool<Postgres>,
+    ) -> Result<Option<TaskStatus>, anyhow::Error> {
+        let task = sqlx::query_as::<_, TaskStatus>(
+            r#"
+            SELECT job_id AS task_id, title, status, progress, log, track_id, error,
+                download_percent, download_speed, download_eta, convert_percent
+            FROM jobs WHERE job_id = $1"#,
+        )
+        .bind(job_id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(task)
+    }
+
+    pub async fn fetch_all_tasks(pool: &Pool<Postgres>) -> Result<Vec<TaskStatus>, anyhow::Error> {
+        let tasks = sqlx::query_as::<_, TaskStatus>(
+            r#"
+            SELECT job_id AS task_id, title, status, progress, log, track_id, error,
+                download_percent, download_speed, download_eta, convert_percent
+            FROM jobs ORDER BY created_at"#,
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(tasks)
+    }
+}
+
+// A clip bound as stored in the `INTEGER` clip columns.
+fn clip_column(secs: Option<u32>) -> Result<Option<i32>, anyhow::Error> {
+    secs.map(i32::try_from)
+        .transpose()
+        .context("clip bound out of range")
 }
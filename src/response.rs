@@ -0,0 +1,348 @@
+use serde::Serialize;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+pub const CONTENT_TYPE_JSON: &str = "application/json";
+pub const CONTENT_TYPE_TEXT: &str = "text/plain; charset=utf-8";
+pub const CONTENT_TYPE_MP3: &str = "audio/mpeg";
+pub const CONTENT_TYPE_M3U8: &str = "application/vnd.apple.mpegurl";
+pub const CONTENT_TYPE_TS: &str = "video/mp2t";
+pub const CONTENT_TYPE_MP4: &str = "audio/mp4";
+pub const CONTENT_TYPE_MPD: &str = "application/dash+xml";
+
+const CORS_ALLOW_METHODS: &str = "POST, GET, OPTIONS, HEAD, DELETE";
+const CORS_ALLOW_HEADERS: &str = "Content-Type, Range, If-Range, If-None-Match, If-Modified-Since";
+const CORS_EXPOSE_HEADERS: &str = "Content-Length, Content-Range, Accept-Ranges, ETag";
+
+/// Buffer size for copying file bodies, so memory per listener stays flat.
+const FILE_CHUNK: usize = 64 * 1024;
+
+/// Response headers, kept in insertion order and compared case-insensitively.
+#[derive(Debug, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// Sets a header, replacing any previous value under the same name.
+    pub fn insert(&mut self, name: &str, value: impl Into<String>) {
+        let value = value.into();
+        match self
+            .0
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        {
+            Some(entry) => entry.1 = value,
+            None => self.0.push((name.to_string(), value)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// `len` bytes of `file` starting at `offset`, copied to the socket as it is written.
+    File {
+        file: File,
+        offset: u64,
+        len: u64,
+    },
+    /// Several ranges of `file`, each preceded by its part header, then `trailer`
+    /// (a `multipart/byteranges` body).
+    FileParts {
+        file: File,
+        parts: Vec<FilePart>,
+        trailer: Vec<u8>,
+    },
+    /// Chunks of unknown total length, sent with chunked transfer coding.
+    Stream(mpsc::Receiver<Vec<u8>>),
+}
+
+pub struct FilePart {
+    pub header: Vec<u8>,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Body {
+    fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File { len, .. } => Some(*len),
+            Body::FileParts { parts, trailer, .. } => Some(
+                parts
+                    .iter()
+                    .map(|part| part.header.len() as u64 + part.len)
+                    .sum::<u64>()
+                    + trailer.len() as u64,
+            ),
+            Body::Stream(_) => None,
+        }
+    }
+}
+
+// Response produced by a handler; the server serialises it onto the connection.
+pub struct Response {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Headers::default(),
+            body: Body::Empty,
+        }
+    }
+
+    pub fn ok() -> Self {
+        Self::new(200)
+    }
+
+    pub fn text(status: u16, text: impl Into<String>) -> Self {
+        Self::new(status).bytes(CONTENT_TYPE_TEXT, text.into().into_bytes())
+    }
+
+    pub fn json<T: Serialize + ?Sized>(status: u16, value: &T) -> serde_json::Result<Self> {
+        let body = serde_json::to_vec(value)?;
+        Ok(Self::new(status).bytes(CONTENT_TYPE_JSON, body))
+    }
+
+    pub fn internal_error() -> Self {
+        Self::text(500, "500 Internal Server Error")
+    }
+
+    /// Answer to a CORS preflight (`OPTIONS`) request.
+    pub fn preflight() -> Self {
+        Self::new(204)
+            .header("Access-Control-Allow-Methods", CORS_ALLOW_METHODS)
+            .header("Access-Control-Allow-Headers", CORS_ALLOW_HEADERS)
+            .header("Access-Control-Max-Age", "86400")
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn bytes(mut self, content_type: &str, body: Vec<u8>) -> Self {
+        self.headers.insert("Content-Type", content_type);
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    pub fn file(mut self, content_type: &str, file: File, offset: u64, len: u64) -> Self {
+        self.headers.insert("Content-Type", content_type);
+        self.body = Body::File { file, offset, len };
+        self
+    }
+
+    pub fn file_parts(
+        mut self,
+        content_type: &str,
+        file: File,
+        parts: Vec<FilePart>,
+        trailer: Vec<u8>,
+    ) -> Self {
+        self.headers.insert("Content-Type", content_type);
+        self.body = Body::FileParts {
+            file,
+            parts,
+            trailer,
+        };
+        self
+    }
+
+    pub fn stream(mut self, content_type: &str, chunks: mpsc::Receiver<Vec<u8>>) -> Self {
+        self.headers.insert("Content-Type", content_type);
+        self.body = Body::Stream(chunks);
+        self
+    }
+
+    /// Drops the body for a `HEAD` answer, keeping the `Content-Length` the
+    /// matching `GET` would have sent.
+    pub fn without_body(mut self) -> Self {
+        if let Some(len) = self.body.len() {
+            self.headers.insert("Content-Length", len.to_string());
+        }
+        self.body = Body::Empty;
+        self
+    }
+
+    /// Status line and headers. `Content-Length` always reflects the body; only
+    /// a bodiless response (HEAD) may announce the length of the resource itself.
+    fn head(&mut self) -> String {
+        self.headers.insert("Access-Control-Allow-Origin", "*");
+        self.headers
+            .insert("Access-Control-Expose-Headers", CORS_EXPOSE_HEADERS);
+        match self.body.len() {
+            _ if self.status == 204 || self.status == 304 => self.headers.remove("content-length"),
+            Some(0) if self.headers.get("content-length").is_some() => {}
+            Some(len) => self.headers.insert("Content-Length", len.to_string()),
+            None => {
+                self.headers.remove("content-length");
+                self.headers.insert("Transfer-Encoding", "chunked");
+            }
+        }
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    pub async fn write_to(mut self, socket: &mut TcpStream) -> std::io::Result<()> {
+        let head = self.head();
+        socket.write_all(head.as_bytes()).await?;
+        match self.body {
+            Body::Empty => Ok(()),
+            Body::Bytes(bytes) => socket.write_all(&bytes).await,
+            Body::File {
+                mut file,
+                offset,
+                len,
+            } => send_file(socket, &mut file, offset, len).await,
+            Body::FileParts {
+                mut file,
+                parts,
+                trailer,
+            } => {
+                for part in parts {
+                    socket.write_all(&part.header).await?;
+                    send_file(socket, &mut file, part.offset, part.len).await?;
+                }
+                socket.write_all(&trailer).await
+            }
+            Body::Stream(mut chunks) => {
+                while let Some(chunk) = chunks.recv().await {
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    socket
+                        .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                        .await?;
+                    socket.write_all(&chunk).await?;
+                    socket.write_all(b"\r\n").await?;
+                    socket.flush().await?;
+                }
+                socket.write_all(b"0\r\n\r\n").await
+            }
+        }
+    }
+}
+
+// Writes `len` bytes of `file` from `offset`, handing the copy to the kernel
+// where possible.
+async fn send_file(
+    socket: &mut TcpStream,
+    file: &mut File,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    let sent = sendfile(socket, file, offset, len).await?;
+    #[cfg(not(target_os = "linux"))]
+    let sent = 0;
+
+    // Whatever sendfile could not take goes through a fixed buffer
+    let mut remaining = len - sent;
+    if remaining == 0 {
+        return Ok(());
+    }
+    file.seek(SeekFrom::Start(offset + sent)).await?;
+    let mut buffer = vec![0; FILE_CHUNK];
+    while remaining > 0 {
+        let want = remaining.min(FILE_CHUNK as u64) as usize;
+        let read = file.read(&mut buffer[..want]).await?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        socket.write_all(&buffer[..read]).await?;
+        remaining -= read as u64;
+    }
+    Ok(())
+}
+
+// Zero-copy file to socket transfer. Returns how many bytes were sent; stops
+// early (without error) when the file system does not support sendfile.
+#[cfg(target_os = "linux")]
+async fn sendfile(
+    socket: &mut TcpStream,
+    file: &File,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<u64> {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    let socket_fd = socket.as_raw_fd();
+    let file_fd = file.as_raw_fd();
+    let mut position = offset as libc::off_t;
+    let mut sent = 0;
+    while sent < len {
+        let count = (len - sent).min(FILE_CHUNK as u64 * 16) as usize;
+        socket.writable().await?;
+        let result = socket.try_io(Interest::WRITABLE, || {
+            // SAFETY: both descriptors stay open for the duration of the call
+            // and `position` is a valid, exclusively borrowed offset.
+            let n = unsafe { libc::sendfile(socket_fd, file_fd, &mut position, count) };
+            if n < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(n as u64)
+            }
+        });
+        match result {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => sent += n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(e)
+                if sent == 0 && matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) =>
+            {
+                return Ok(0);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sent)
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        _ => "Unknown",
+    }
+}
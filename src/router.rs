@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use request_http_parser::parser::Method;
+use request_http_parser::parser::Request;
+
+use crate::error::ApiError;
+use crate::response::Response;
+use crate::server::AppState;
+
+/// Percent-decoded values captured from `:name` segments of a route pattern.
+pub type Params = HashMap<String, String>;
+
+type BoxFuture = Pin<Box<dyn Future<Output = Result<Response, ApiError>> + Send>>;
+type Handler = Box<dyn Fn(Request, Params, AppState) -> BoxFuture + Send + Sync>;
+
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+impl Route {
+    fn matches(&self, path: &str) -> Option<Params> {
+        let parts: Vec<&str> = split_path(path).collect();
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = Params::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => {
+                    let value = percent_encoding::percent_decode_str(part).decode_utf8_lossy();
+                    params.insert(name.clone(), value.to_string());
+                }
+            }
+        }
+        Some(params)
+    }
+}
+
+/// Dispatches requests to handlers registered per method and path pattern.
+///
+/// Patterns are `/`-separated and may contain `:name` segments, e.g.
+/// `/tracks/:id/playlist.m3u8`. A path that matches a pattern under another
+/// method gets a `405` with an `Allow` header, anything else a `404`. `GET`
+/// routes also answer `HEAD` with the same headers and no body.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route<F, Fut>(mut self, method: Method, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Request, Params, AppState) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, ApiError>> + Send + 'static,
+    {
+        let segments = split_path(pattern)
+            .map(|s| match s.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Literal(s.to_string()),
+            })
+            .collect();
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: Box::new(move |req, params, state| Box::pin(handler(req, params, state))),
+        });
+        self
+    }
+
+    pub fn get<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Request, Params, AppState) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, ApiError>> + Send + 'static,
+    {
+        self.route(Method::GET, pattern, handler)
+    }
+
+    pub fn post<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Request, Params, AppState) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, ApiError>> + Send + 'static,
+    {
+        self.route(Method::POST, pattern, handler)
+    }
+
+    pub fn delete<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Request, Params, AppState) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, ApiError>> + Send + 'static,
+    {
+        self.route(Method::DELETE, pattern, handler)
+    }
+
+    pub async fn handle(&self, request: Request, state: AppState) -> Response {
+        if request.method == Method::OPTIONS {
+            return Response::preflight();
+        }
+
+        // GET routes answer HEAD too, unless a dedicated HEAD route exists
+        let mut head_via_get = None;
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let Some(params) = route.matches(&request.path) else {
+                continue;
+            };
+            if route.method == request.method {
+                return Self::run(route, request, params, state).await;
+            }
+            if route.method == Method::GET
+                && request.method == Method::HEAD
+                && head_via_get.is_none()
+            {
+                head_via_get = Some((route, params));
+            }
+            let mut names = vec![method_name(&route.method)];
+            if route.method == Method::GET {
+                names.push("HEAD");
+            }
+            for name in names {
+                if !allowed.contains(&name) {
+                    allowed.push(name);
+                }
+            }
+        }
+
+        if let Some((route, params)) = head_via_get {
+            return Self::run(route, request, params, state)
+                .await
+                .without_body();
+        }
+
+        if allowed.is_empty() {
+            ApiError::not_found(format!("no route for {}", request.path)).into_response()
+        } else {
+            allowed.push("OPTIONS");
+            let message = format!(
+                "{} is not allowed on {}",
+                method_name(&request.method),
+                request.path
+            );
+            let mut response = ApiError::MethodNotAllowed(message).into_response();
+            response.headers.insert("Allow", allowed.join(", "));
+            response
+        }
+    }
+
+    async fn run(route: &Route, request: Request, params: Params, state: AppState) -> Response {
+        match (route.handler)(request, params, state).await {
+            Ok(response) => response,
+            Err(e) => e.into_response(),
+        }
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+pub fn method_name(method: &Method) -> &'static str {
+    match method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+    }
+}
@@ -0,0 +1,182 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::ApiError;
+
+/// Resolves `relative` against the media `root` and returns the canonical path.
+///
+/// Absolute paths, `..` components and NUL bytes are rejected up front with a
+/// `400`. What is left is canonicalised, so a symlink pointing out of the root
+/// is caught as well; anything that does not exist or lands outside `root` is
+/// reported as `404` to avoid telling clients what lives elsewhere on disk.
+pub async fn resolve(root: &Path, relative: impl AsRef<Path>) -> Result<PathBuf, ApiError> {
+    let relative = relative.as_ref();
+    check_relative(relative)?;
+
+    let not_found = || ApiError::not_found(format!("`{}` not found", relative.display()));
+    let root = tokio::fs::canonicalize(root).await?;
+    let path = tokio::fs::canonicalize(root.join(relative))
+        .await
+        .map_err(|_| not_found())?;
+    if !path.starts_with(&root) {
+        eprintln!(
+            "refusing {} outside media root {}",
+            path.display(),
+            root.display()
+        );
+        return Err(not_found());
+    }
+    Ok(path)
+}
+
+fn check_relative(relative: &Path) -> Result<(), ApiError> {
+    let invalid = || ApiError::bad_request(format!("invalid path `{}`", relative.display()));
+    if relative.as_os_str().is_empty() || relative.as_os_str().as_encoded_bytes().contains(&0) {
+        return Err(invalid());
+    }
+    for component in relative.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(invalid());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use request_http_parser::parser::Request;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::downloader::FakeDownloader;
+    use crate::response::Response;
+    use crate::router::{Params, Router};
+    use crate::server::Server;
+    use crate::transcoder::FakeTranscoder;
+
+    // `<tmp>/<uuid>/root/track.mp3` next to `<tmp>/<uuid>/secret`, removed
+    // when dropped.
+    struct MediaRoot(PathBuf);
+
+    impl MediaRoot {
+        async fn new() -> Self {
+            let base = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            let root = base.join("root");
+            tokio::fs::create_dir_all(&root).await.unwrap();
+            tokio::fs::write(root.join("track.mp3"), b"mp3")
+                .await
+                .unwrap();
+            tokio::fs::write(base.join("secret"), b"secret")
+                .await
+                .unwrap();
+            Self(root)
+        }
+    }
+
+    impl std::ops::Deref for MediaRoot {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for MediaRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    async fn status(root: &Path, relative: &str) -> u16 {
+        match resolve(root, relative).await {
+            Ok(_) => 200,
+            Err(e) => e.status(),
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_files_inside_the_root() {
+        let root = MediaRoot::new().await;
+        let path = resolve(&root, "./track.mp3").await.unwrap();
+        assert!(path.ends_with("root/track.mp3"));
+        assert_eq!(status(&root, "missing.mp3").await, 404);
+    }
+
+    #[tokio::test]
+    async fn rejects_parent_components() {
+        let root = MediaRoot::new().await;
+        assert_eq!(status(&root, "../secret").await, 400);
+        assert_eq!(status(&root, "a/../../secret").await, 400);
+        assert_eq!(status(&root, "..").await, 400);
+    }
+
+    #[tokio::test]
+    async fn rejects_absolute_paths() {
+        let root = MediaRoot::new().await;
+        let secret = root.parent().unwrap().join("secret");
+        assert_eq!(status(&root, secret.to_str().unwrap()).await, 400);
+        assert_eq!(status(&root, "/etc/passwd").await, 400);
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_paths_and_nul_bytes() {
+        let root = MediaRoot::new().await;
+        assert_eq!(status(&root, "").await, 400);
+        assert_eq!(status(&root, "track.mp3\0.txt").await, 400);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn rejects_symlinks_out_of_the_root() {
+        let root = MediaRoot::new().await;
+        tokio::fs::symlink(root.parent().unwrap().join("secret"), root.join("link"))
+            .await
+            .unwrap();
+        tokio::fs::symlink("..", root.join("up")).await.unwrap();
+        assert_eq!(status(&root, "link").await, 404);
+        assert_eq!(status(&root, "up/secret").await, 404);
+    }
+
+    #[tokio::test]
+    async fn rejects_percent_encoded_traversal_in_route_params() {
+        let root = Arc::new(MediaRoot::new().await);
+        let handler_root = Arc::clone(&root);
+        let router = Router::new().get("/files/:name", move |_, params: Params, _| {
+            let root = Arc::clone(&handler_root);
+            async move {
+                resolve(&root, &params["name"]).await?;
+                Ok(Response::ok())
+            }
+        });
+        // Never connects: the handler does not touch the database
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap();
+        let config = Config::default();
+        let transcoder = FakeTranscoder::new(&config.encoding);
+        let state = Server::with_tools(
+            config,
+            pool,
+            Arc::new(FakeDownloader::default()),
+            Arc::new(transcoder),
+        )
+        .state;
+
+        for (path, expected) in [
+            ("/files/track.mp3", 200),
+            ("/files/..%2Fsecret", 400),
+            ("/files/%2E%2E%2Fsecret", 400),
+            ("/files/%2Fetc%2Fpasswd", 400),
+            ("/files/track.mp3%00", 400),
+        ] {
+            let request = Request::new(&format!("GET {} HTTP/1.1\r\n\r\n", path)).unwrap();
+            let response = router.handle(request, state.clone()).await;
+            assert_eq!(response.status, expected, "{}", path);
+        }
+    }
+}
@@ -1,45 +1,145 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::constants::OPTIONS_CORS;
-use crate::constants::{BAD_REQUEST, NOT_FOUND};
+use crate::config::Config;
+use crate::downloader::{Downloader, FakeDownloader, YtDlp};
+use crate::error::ApiError;
+use crate::events::TaskEvents;
 use crate::file::File;
-use crate::hls::HlsService;
+use crate::hls::{HlsService, Mp3Segments};
+use crate::http::{BodyTooLarge, Connection};
+use crate::router::Router;
 use crate::stream::Stream;
 use crate::track::TrackService;
+use crate::transcoder::{FakeTranscoder, Ffmpeg, Transcoder};
+use crate::worker::{JobQueue, Worker};
 use anyhow::anyhow;
 use anyhow::{Context, Result};
-use request_http_parser::parser::{
-    Method::GET, Method::HEAD, Method::OPTIONS, Method::POST, Request,
-};
 use sqlx::{Pool, Postgres};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::oneshot::Receiver;
 
-pub struct Server {
+// Shared state handed to every handler.
+#[derive(Clone)]
+pub struct AppState {
     pub pool: Arc<Pool<Postgres>>,
+    pub config: Arc<Config>,
+    pub queue: JobQueue,
+    pub events: TaskEvents,
+    pub downloader: Arc<dyn Downloader>,
+    pub transcoder: Arc<dyn Transcoder>,
+    pub mp3_segments: Mp3Segments,
+}
+
+pub struct Server {
+    pub state: AppState,
+    router: Arc<Router>,
 }
 
 impl Server {
-    pub fn new(pool: Pool<Postgres>) -> Self {
-        let pool = Arc::new(pool);
-        Self { pool }
+    /// Server using yt-dlp and ffmpeg, or the built-in fakes when
+    /// `tools.fake` is set.
+    pub fn new(config: Config, pool: Pool<Postgres>) -> Self {
+        let tools = &config.tools;
+        let (downloader, transcoder): (Arc<dyn Downloader>, Arc<dyn Transcoder>) = if tools.fake {
+            println!("using fake downloader and transcoder");
+            (
+                Arc::new(FakeDownloader::default()),
+                Arc::new(FakeTranscoder::new(&config.encoding)),
+            )
+        } else {
+            (
+                Arc::new(YtDlp::new(&tools.yt_dlp)),
+                Arc::new(Ffmpeg::new(&tools.ffmpeg, config.encoding.clone())),
+            )
+        };
+        Self::with_tools(config, pool, downloader, transcoder)
+    }
+
+    pub fn with_tools(
+        config: Config,
+        pool: Pool<Postgres>,
+        downloader: Arc<dyn Downloader>,
+        transcoder: Arc<dyn Transcoder>,
+    ) -> Self {
+        let state = AppState {
+            pool: Arc::new(pool),
+            config: Arc::new(config),
+            queue: JobQueue::new(),
+            events: TaskEvents::new(),
+            downloader,
+            transcoder,
+            mp3_segments: Mp3Segments::new(),
+        };
+        Self {
+            state,
+            router: Arc::new(Self::routes()),
+        }
+    }
+
+    fn routes() -> Router {
+        Router::new()
+            .get("/stream", Stream::stream_song)
+            .post("/stream", |req, _, state| Stream::add_song(req, state))
+            .get("/search", |req, _, state| Stream::search_song(req, state))
+            .get("/playlist", HlsService::serve_hls_playlist1)
+            .get("/segment", HlsService::serve_hls_segment1)
+            .get("/tracks", |_, _, state| TrackService::query_track(state))
+            .get("/tracks/:id", |_, params, state| {
+                TrackService::get_track(params, state)
+            })
+            .get("/tracks/:id/stream", Stream::stream_song)
+            .get("/tracks/:id/playlist.m3u8", HlsService::serve_hls_playlist1)
+            .get("/tracks/:id/mp3.m3u8", HlsService::serve_hls_playlist)
+            .get("/tracks/:id/mp3-segment", HlsService::serve_hls_segment)
+            .get("/tracks/:id/variants/:file", HlsService::serve_hls_variant)
+            .get("/tracks/:id/manifest.mpd", HlsService::serve_dash_manifest)
+            .get("/tracks/:id/segments/:file", HlsService::serve_hls_segment1)
+            .post("/download", |req, _, state| File::download_task(req, state))
+            .get("/task-status", |req, _, state| {
+                File::get_task_status(req, state)
+            })
+            .get("/tasks", |_, _, state| File::list_tasks(state))
+            .get("/tasks/events", |req, _, state| {
+                File::task_events(req, None, state)
+            })
+            .get("/tasks/:id", |_, params, state| {
+                File::get_task(params, state)
+            })
+            .get("/tasks/:id/events", |req, params, state| {
+                File::task_events(req, params.get("id").cloned(), state)
+            })
+            .delete("/tasks/:id", |_, params, state| {
+                File::delete_task(params, state)
+            })
+            .post("/tasks/:id/retry", |_, params, state| {
+                File::retry_task(params, state)
+            })
+            .get("/track", |_, _, state| TrackService::query_track(state))
     }
+
     pub async fn start(&self, mut shutdown_rx: Receiver<()>) -> anyhow::Result<()> {
-        let listener = TcpListener::bind("0.0.0.0:3001")
+        let bind = &self.state.config.server.bind;
+        let listener = TcpListener::bind(bind)
             .await
-            .expect("failed to binding port");
-        println!("Server running on http://0.0.0.0:3001");
+            .with_context(|| format!("failed to bind {}", bind))?;
+        println!("Server running on http://{}", bind);
+
+        Worker::start_pool(self.state.clone())
+            .await
+            .context("failed to start download workers")?;
 
         loop {
             tokio::select! {
                 conn = listener.accept() => {
                     let ( stream, _) = conn?;
 
-                    let pool = Arc::clone(&self.pool);
+                    let state = self.state.clone();
+                    let router = Arc::clone(&self.router);
 
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_client(stream,&pool).await {
+                        if let Err(e) = Self::handle_client(stream, &router, state).await {
                             eprintln!("Connection error: {}", e);
                         }
                     });
@@ -54,68 +154,41 @@ impl Server {
         Ok(())
     }
 
-    async fn handle_client(mut socket: TcpStream, pool: &Arc<Pool<Postgres>>) -> Result<()> {
-        let mut buffer = [0; 1024];
-        if let Ok(n) = socket.read(&mut buffer).await {
-            let req_str = String::from_utf8_lossy(&buffer[..n]);
-            println!("req:\n {}", req_str);
-            let request = match Request::new(&req_str) {
-                Ok(req) => req,
+    async fn handle_client(socket: TcpStream, router: &Router, state: AppState) -> Result<()> {
+        let mut conn = Connection::new(socket);
+        let idle = Duration::from_secs(state.config.server.keep_alive_secs);
+        loop {
+            let (request, keep_alive) = match conn.read_request(idle).await {
+                Ok(Some(req)) => req,
+                Ok(None) => return Ok(()),
                 Err(e) => {
                     println!("{}", e);
-                    let _ = socket
-                        .write_all(format!("{}{}", BAD_REQUEST, e).as_bytes())
-                        .await
-                        .context("Failed to write");
-
-                    let _ = socket.flush().await.context("Failed to flush");
+                    let error = if e.is::<BodyTooLarge>() {
+                        ApiError::PayloadTooLarge(e.to_string())
+                    } else {
+                        ApiError::bad_request(e.to_string())
+                    };
+                    let _ = error
+                        .into_response()
+                        .header("Connection", "close")
+                        .write_to(conn.socket())
+                        .await;
+                    let _ = conn.socket().flush().await.context("Failed to flush");
                     return Err(anyhow!("request format invalid"));
                 }
             };
             println!("metod {:?} path {:?}", request.method, request.path);
-            match (&request.method, request.path.as_str()) {
-                (OPTIONS, _) => {
-                    let _ = socket
-                        .write_all(format!("{}{}", OPTIONS_CORS, "").as_bytes())
-                        .await;
-                }
-                (HEAD, "/stream") => Stream::get_info(socket, request)
-                    .await
-                    .expect("error head stream"),
-                (GET, "/stream") => Stream::stream_song(socket, request)
-                    .await
-                    .expect("error handle"),
-                (POST, "/stream") => Stream::add_song(socket, request).await.expect("error add"),
-                (GET, "/search") => Stream::search_song(socket, request)
-                    .await
-                    .expect("error search"),
-                (GET, "/playlist") => HlsService::serve_hls_playlist1(socket, request)
-                    .await
-                    .expect("error handle"),
-                (GET, "/segment") => HlsService::serve_hls_segment1(socket, request)
-                    .await
-                    .expect("error handle"),
-                (POST, "/download") => File::download_task(socket, request, pool.clone())
-                    .await
-                    .expect("error downdload"),
 
-                (GET, "/task-status") => File::get_task_status(socket, request)
-                    .await
-                    .expect("task status failed"),
-                (GET, "/track") => TrackService::query_track(socket, request, pool.clone())
-                    .await
-                    .expect("track query failed"),
-                _ => {
-                    let _ = socket
-                        .write_all(format!("{}{}", NOT_FOUND, "404 Not Found").as_bytes())
-                        .await;
-                }
-            };
+            let mut response = router.handle(request, state.clone()).await;
+            let connection = if keep_alive { "keep-alive" } else { "close" };
+            response.headers.insert("Connection", connection);
+            let socket = conn.socket();
+            response.write_to(socket).await?;
+            socket.flush().await.context("Failed to flush")?;
 
-            Ok(())
-        } else {
-            println!("error ");
-            Ok(())
+            if !keep_alive {
+                return Ok(());
+            }
         }
     }
 }
@@ -1,39 +1,56 @@
-use crate::model::GetTrack;
-use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-
 use request_http_parser::parser::Request;
-use sqlx::{Pool, Postgres};
-use tokio::net::TcpStream;
 
-use crate::{
-    constants::{NOT_FOUND, OK_RESPONSE},
-    repo::Repository,
-};
+use crate::http::query_param;
+use crate::model::{GetTrack, Track};
+use crate::router::Params;
+
+use crate::{error::ApiError, repo::Repository, response::Response, server::AppState};
 
 pub struct TrackService {}
 
 impl TrackService {
-    pub async fn query_track(
-        mut socket: TcpStream,
-        _request: Request,
-        pool: Arc<Pool<Postgres>>,
-    ) -> std::io::Result<()> {
-        let tracks = match Repository::fetch_all_tracks(&pool).await {
-            Ok(tracks) => tracks,
-            Err(e) => {
-                println!("{:?}", e);
-                let _ = socket
-                    .write_all(format!("{}{}", NOT_FOUND, "404 Not Found").as_bytes())
-                    .await;
-                return Ok(());
-            }
-        };
-        let json: String = serde_json::to_string::<Vec<GetTrack>>(&tracks).expect("error serde");
-        socket
-            .write_all(format!("{}{}", OK_RESPONSE, json).as_bytes())
-            .await
-            .expect("Failed to write");
-        Ok(())
+    pub async fn query_track(state: AppState) -> Result<Response, ApiError> {
+        let tracks = Repository::fetch_all_tracks(&state.pool).await?;
+        Ok(Response::json::<Vec<GetTrack>>(200, &tracks)?)
+    }
+
+    pub async fn get_track(params: Params, state: AppState) -> Result<Response, ApiError> {
+        let track = Self::by_id(&params, &state).await?;
+        Ok(Response::json(
+            200,
+            &GetTrack {
+                track_id: track.track_id.unwrap_or_default(),
+                title: track.title,
+                duration: track.duration,
+            },
+        )?)
+    }
+
+    /// Looks up the track a streaming request refers to: the `:id` path segment
+    /// on `/tracks/:id/...` routes, or the legacy `?song=<title>` query.
+    pub async fn resolve(
+        request: &Request,
+        params: &Params,
+        state: &AppState,
+    ) -> Result<Track, ApiError> {
+        if params.contains_key("id") {
+            return Self::by_id(params, state).await;
+        }
+        let song = query_param(request, "song")?;
+        Repository::fetch_track_by_title(&song, &state.pool)
+            .await?
+            .ok_or_else(|| ApiError::not_found(format!("song `{}` not found", song)))
+    }
+
+    async fn by_id(params: &Params, state: &AppState) -> Result<Track, ApiError> {
+        let id = params
+            .get("id")
+            .ok_or_else(|| ApiError::bad_request("missing track id"))?;
+        let track_id: i32 = id
+            .parse()
+            .map_err(|_| ApiError::bad_request(format!("invalid track id `{}`", id)))?;
+        Repository::fetch_track(track_id, &state.pool)
+            .await?
+            .ok_or_else(|| ApiError::not_found(format!("track {} not found", track_id)))
     }
 }
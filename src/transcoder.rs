@@ -0,0 +1,244 @@
+use std::path::Path;
+
+use anyhow::{Context, bail};
+use tokio::process::Command;
+
+use crate::config::{EncodingConfig, SegmentFormat, parse_bitrate};
+use crate::downloader::{SILENT_FRAME_LEN, SILENT_FRAME_SECS, ToolFuture};
+use crate::m3u8::{Map, MasterPlaylist, MediaPlaylist, PlaylistType, Segment, VariantStream};
+use crate::process::{self, ToolOutput};
+
+/// Master playlist written into the output folder of a track. Each rendition
+/// has a media playlist named after its bitrate next to it, e.g. `64k.m3u8`.
+pub const PLAYLIST: &str = "index.m3u8";
+// `%v` is the rendition name
+const VARIANT_PATTERN: &str = "%v.m3u8";
+const SEGMENT_PATTERN: &str = "%v_segment_%03d";
+// Init segment of each rendition in fMP4 mode
+const INIT_PATTERN: &str = "%v_init.mp4";
+
+/// Turns a downloaded MP3 into HLS.
+pub trait Transcoder: Send + Sync {
+    /// Writes `PLAYLIST`, the media playlist of every rendition and their
+    /// segments into `dir`, which must exist. Returns the container of the
+    /// segments.
+    fn to_hls<'a>(
+        &'a self,
+        input: &'a Path,
+        dir: &'a Path,
+        output: ToolOutput,
+    ) -> ToolFuture<'a, SegmentFormat>;
+}
+
+/// Transcoder backed by the `ffmpeg` executable.
+pub struct Ffmpeg {
+    program: String,
+    encoding: EncodingConfig,
+}
+
+impl Ffmpeg {
+    pub fn new(program: impl Into<String>, encoding: EncodingConfig) -> Self {
+        Self {
+            program: program.into(),
+            encoding,
+        }
+    }
+}
+
+impl Transcoder for Ffmpeg {
+    fn to_hls<'a>(
+        &'a self,
+        input: &'a Path,
+        dir: &'a Path,
+        output: ToolOutput,
+    ) -> ToolFuture<'a, SegmentFormat> {
+        Box::pin(async move {
+            let enc = &self.encoding;
+            let mut command = Command::new(&self.program);
+            command.arg("-y").arg("-i").arg(input);
+            // One encoded copy of the audio per rendition
+            let mut stream_map = Vec::new();
+            for (i, bitrate) in enc.bitrates.iter().enumerate() {
+                command
+                    .args(["-map", "0:a"])
+                    .arg(format!("-b:a:{}", i))
+                    .arg(bitrate);
+                stream_map.push(format!("a:{},name:{}", i, bitrate));
+            }
+            command
+                .args(["-c:a", &enc.audio_codec])
+                .args(["-ac", &enc.channels.to_string()])
+                .args(["-ar", &enc.sample_rate.to_string()])
+                .args(["-f", "hls", "-hls_time", &enc.hls_time.to_string()])
+                .args(["-hls_playlist_type", "vod"]);
+            if enc.segment_format == SegmentFormat::Fmp4 {
+                command
+                    .args(["-hls_segment_type", "fmp4"])
+                    .args(["-hls_fmp4_init_filename", INIT_PATTERN]);
+            }
+            let extension = enc.segment_format.extension();
+            command
+                .arg("-hls_segment_filename")
+                .arg(dir.join(format!("{}.{}", SEGMENT_PATTERN, extension)))
+                .arg("-var_stream_map")
+                .arg(stream_map.join(" "))
+                .arg(dir.join(VARIANT_PATTERN));
+            let run = process::run(command, &output).await?;
+            if !run.status.success() {
+                bail!("ffmpeg failed ({})", run.status);
+            }
+            let version = enc.segment_format.playlist_version();
+            write_master(dir, version, &enc.audio_codec, &enc.bitrates).await?;
+            Ok(enc.segment_format)
+        })
+    }
+}
+
+// Writes the master playlist listing one rendition per bitrate.
+async fn write_master(
+    dir: &Path,
+    version: u32,
+    audio_codec: &str,
+    bitrates: &[String],
+) -> anyhow::Result<()> {
+    let variants = bitrates
+        .iter()
+        .map(|bitrate| VariantStream {
+            // Peak rate, with some room for the container overhead
+            bandwidth: parse_bitrate(bitrate).unwrap_or_default() * 11 / 10,
+            codecs: codecs(audio_codec).map(str::to_string),
+            uri: VARIANT_PATTERN.replace("%v", bitrate),
+            ..Default::default()
+        })
+        .collect();
+    let playlist = MasterPlaylist {
+        version: Some(version),
+        variants,
+        ..Default::default()
+    };
+    tokio::fs::write(dir.join(PLAYLIST), playlist.to_string())
+        .await
+        .context("failed to write master playlist")
+}
+
+// RFC 6381 codec string of an ffmpeg audio encoder, when known.
+fn codecs(audio_codec: &str) -> Option<&'static str> {
+    match audio_codec {
+        "aac" | "libfdk_aac" => Some("mp4a.40.2"),
+        "mp3" | "libmp3lame" => Some("mp4a.40.34"),
+        "ac3" => Some("ac-3"),
+        "eac3" => Some("ec-3"),
+        _ => None,
+    }
+}
+
+/// Offline transcoder for tests. Cuts the input into `hls_time` second
+/// segments of whole frames of the fake downloader's silent MP3, without
+/// re-encoding, so every rendition carries the same data. In fMP4 mode the
+/// init segment is a bare `ftyp` box and the segments stay plain MP3.
+pub struct FakeTranscoder {
+    pub hls_time: u32,
+    pub bitrates: Vec<String>,
+    pub segment_format: SegmentFormat,
+}
+
+impl FakeTranscoder {
+    pub fn new(encoding: &EncodingConfig) -> Self {
+        Self {
+            hls_time: encoding.hls_time,
+            bitrates: encoding.bitrates.clone(),
+            segment_format: encoding.segment_format,
+        }
+    }
+}
+
+impl Transcoder for FakeTranscoder {
+    fn to_hls<'a>(
+        &'a self,
+        input: &'a Path,
+        dir: &'a Path,
+        output: ToolOutput,
+    ) -> ToolFuture<'a, SegmentFormat> {
+        Box::pin(async move {
+            let data = tokio::fs::read(input)
+                .await
+                .with_context(|| format!("failed to read {}", input.display()))?;
+            let frames = data.len() / SILENT_FRAME_LEN;
+            let _ = output.send(format!(
+                "  Duration: {}, start: 0.000000, bitrate: 128 kb/s",
+                Self::clock(frames as f64 * SILENT_FRAME_SECS)
+            ));
+
+            let per_segment = ((self.hls_time as f64 / SILENT_FRAME_SECS) as usize).max(1);
+            let version = self.segment_format.playlist_version();
+            let extension = self.segment_format.extension();
+            let mut playlist = MediaPlaylist {
+                version: Some(version),
+                target_duration: self.hls_time as u64,
+                playlist_type: Some(PlaylistType::Vod),
+                end_list: true,
+                ..Default::default()
+            };
+            let mut written = 0;
+            for (index, chunk) in data.chunks(per_segment * SILENT_FRAME_LEN).enumerate() {
+                for bitrate in &self.bitrates {
+                    let name = format!("{}_segment_{:03}.{}", bitrate, index, extension);
+                    tokio::fs::write(dir.join(&name), chunk)
+                        .await
+                        .with_context(|| format!("failed to write segment {}", name))?;
+                }
+                playlist.segments.push(Segment {
+                    duration: (chunk.len() / SILENT_FRAME_LEN) as f64 * SILENT_FRAME_SECS,
+                    uri: format!("segment_{:03}.{}", index, extension),
+                    ..Default::default()
+                });
+                written += chunk.len() / SILENT_FRAME_LEN;
+                let _ = output.send(format!(
+                    "size=N/A time={} bitrate=N/A speed=1x",
+                    Self::clock(written as f64 * SILENT_FRAME_SECS)
+                ));
+            }
+            if self.segment_format == SegmentFormat::Fmp4
+                && let Some(first) = playlist.segments.first_mut()
+            {
+                first.map = Some(Map {
+                    uri: INIT_PATTERN.replace("%v_", ""),
+                    byte_range: None,
+                });
+                for bitrate in &self.bitrates {
+                    let name = INIT_PATTERN.replace("%v", bitrate);
+                    tokio::fs::write(dir.join(&name), Self::FTYP)
+                        .await
+                        .with_context(|| format!("failed to write init segment {}", name))?;
+                }
+            }
+            for bitrate in &self.bitrates {
+                let mut variant = playlist.clone();
+                variant.map_uris(|uri| format!("{}_{}", bitrate, uri));
+                let name = VARIANT_PATTERN.replace("%v", bitrate);
+                tokio::fs::write(dir.join(&name), variant.to_string())
+                    .await
+                    .with_context(|| format!("failed to write playlist {}", name))?;
+            }
+            write_master(dir, version, "mp3", &self.bitrates).await?;
+            Ok(self.segment_format)
+        })
+    }
+}
+
+impl FakeTranscoder {
+    // `ftyp` box of a CMAF track: major brand `iso6`, compatible `iso6` and `cmfc`.
+    const FTYP: &[u8] = b"\x00\x00\x00\x18ftypiso6\x00\x00\x00\x00iso6cmfc";
+
+    // `hh:mm:ss.ff` as printed by ffmpeg.
+    fn clock(secs: f64) -> String {
+        let whole = secs as u64;
+        format!(
+            "{:02}:{:02}:{:02}.{:02}",
+            whole / 3600,
+            whole / 60 % 60,
+            whole % 60,
+            ((secs - whole as f64) * 100.0) as u64
+        )
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, anyhow};
+use chrono::Utc;
+use sqlx::{Pool, Postgres};
+use tokio::sync::{Notify, mpsc, watch};
+use uuid::Uuid;
+
+use crate::clip;
+use crate::config::Config;
+use crate::downloader::{self, Transient};
+use crate::model::{AddStream, Job, JobStatus, Track};
+use crate::progress::{self, DOWNLOAD_WEIGHT, ProgressUpdate, StageProgress};
+use crate::repo::Repository;
+use crate::server::AppState;
+use crate::transcoder::PLAYLIST;
+
+/// Download jobs live in the `jobs` table; the queue wakes idle workers when
+/// one is added and signals workers whose job was cancelled.
+#[derive(Clone, Default)]
+pub struct JobQueue {
+    notify: Arc<Notify>,
+    running: Arc<Mutex<HashMap<Uuid, watch::Sender<bool>>>>,
+}
+
+impl JobQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a job, unless one for the same source and clip is already
+    /// queued or running.
+    pub async fn enqueue(
+        &self,
+        body: &AddStream,
+        video_id: Option<&str>,
+        pool: &Pool<Postgres>,
+    ) -> Result<Option<Uuid>, anyhow::Error> {
+        let job_id = Uuid::new_v4();
+        if !Repository::insert_job(job_id, body, video_id, pool).await? {
+            return Ok(None);
+        }
+        self.wake();
+        Ok(Some(job_id))
+    }
+
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Asks the worker running `job_id` to stop. Returns false when no worker
+    /// in this process has it.
+    pub fn cancel(&self, job_id: Uuid) -> bool {
+        match self.running.lock().unwrap().get(&job_id) {
+            Some(cancel) => cancel.send(true).is_ok(),
+            None => false,
+        }
+    }
+
+    // Returns the sender identifying this run of the job and its receiver.
+    fn register(&self, job_id: Uuid) -> (watch::Sender<bool>, watch::Receiver<bool>) {
+        let (cancel, cancelled) = watch::channel(false);
+        self.running.lock().unwrap().insert(job_id, cancel.clone());
+        (cancel, cancelled)
+    }
+
+    fn finish(&self, job_id: Uuid, cancel: &watch::Sender<bool>) {
+        let mut running = self.running.lock().unwrap();
+        // A retry of the job may have registered a run of its own meanwhile
+        if running
+            .get(&job_id)
+            .is_some_and(|current| current.same_channel(cancel))
+        {
+            running.remove(&job_id);
+        }
+    }
+
+    async fn wait(&self, poll_interval: Duration) {
+        let _ = tokio::time::timeout(poll_interval, self.notify.notified()).await;
+    }
+}
+
+/// The job was cancelled while a worker was running it.
+#[derive(Debug)]
+struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+pub struct Worker {}
+
+impl Worker {
+    /// Requeues jobs interrupted by the last shutdown and starts
+    /// `jobs.concurrency` workers.
+    pub async fn start_pool(state: AppState) -> anyhow::Result<()> {
+        // Assumes a single server per database: anything still marked as
+        // running was left behind by this server's previous run.
+        let requeued = Repository::requeue_interrupted_jobs(&state.pool).await?;
+        if requeued > 0 {
+            println!("requeued {} interrupted job(s)", requeued);
+        }
+        Self::clear_staging(&state.config).await;
+        for id in 0..state.config.jobs.concurrency {
+            tokio::spawn(Self::run(id, state.clone()));
+        }
+        Ok(())
+    }
+
+    async fn run(id: usize, state: AppState) {
+        let poll_interval = Duration::from_secs(state.config.jobs.poll_interval_secs);
+        loop {
+            match Repository::claim_job(&state.pool).await {
+                Ok(Some(job)) => {
+                    println!("worker {} picked job {}", id, job.job_id);
+                    state.events.task_changed(job.job_id, &state.pool).await;
+                    let (cancel, mut cancelled) = state.queue.register(job.job_id);
+                    // A panicking job fails like any other instead of taking the worker down
+                    let result = tokio::spawn({
+                        let (job, state) = (job.clone(), state.clone());
+                        async move { Self::process(&job, &state, &mut cancelled).await }
+                    })
+                    .await
+                    .unwrap_or_else(|e| Err(anyhow!("job panicked: {}", e)));
+                    state.queue.finish(job.job_id, &cancel);
+                    if let Err(e) = result {
+                        Self::handle_failure(&job, e, &state).await;
+                    }
+                    state.events.task_changed(job.job_id, &state.pool).await;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("worker {} failed to claim a job: {:?}", id, e),
+            }
+            state.queue.wait(poll_interval).await;
+        }
+    }
+
+    async fn handle_failure(job: &Job, e: anyhow::Error, state: &AppState) {
+        if e.is::<Cancelled>() {
+            // A retry right after the cancel may already be writing to the
+            // same folders
+            match Repository::fetch_task(job.job_id, &state.pool).await {
+                Ok(Some(task)) if task.status != JobStatus::Cancelled.as_str() => {
+                    println!(
+                        "job {} cancelled and requeued, keeping its files",
+                        job.job_id
+                    );
+                }
+                _ => {
+                    Self::remove_files(job.job_id, &state.config).await;
+                    println!("job {} cancelled", job.job_id);
+                }
+            }
+            return;
+        }
+        // Nothing of a failed attempt is kept; a retry starts over
+        Self::remove_files(job.job_id, &state.config).await;
+
+        let jobs = &state.config.jobs;
+        let (reason, result) = if e.is::<Transient>() && (job.attempts as u32) < jobs.max_attempts {
+            let exponent = (job.attempts.max(1) - 1).min(16) as u32;
+            let delay = jobs.retry_backoff_secs.saturating_mul(1 << exponent);
+            println!("job {} will be retried in {}s: {:#}", job.job_id, delay, e);
+            let reason = format!("{:#}, retrying in {}s", e, delay);
+            let result = Repository::schedule_retry(job.job_id, delay, &reason, &state.pool).await;
+            (reason, result)
+        } else {
+            eprintln!("job {} failed: {:?}", job.job_id, e);
+            let reason = format!("{:#}", e);
+            let result = Repository::fail_job(job.job_id, &reason, &state.pool).await;
+            (reason, result)
+        };
+        state.events.log(job.job_id, &reason);
+        if let Err(e) = result {
+            eprintln!("failed to record failure of job {}: {:?}", job.job_id, e);
+        }
+    }
+
+    /// Deletes whatever a job wrote to its staging folder and the media folders.
+    pub async fn remove_files(job_id: Uuid, config: &Config) {
+        let prefix = format!("{}.", job_id);
+        if let Ok(mut entries) = tokio::fs::read_dir(&config.storage.mp3_dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+                    continue;
+                }
+                if let Err(e) = tokio::fs::remove_file(entry.path()).await {
+                    eprintln!("failed to remove {}: {}", entry.path().display(), e);
+                }
+            }
+        }
+        for dir in [&config.storage.hls_dir, &config.storage.staging_dir] {
+            Self::remove_dir(&dir.join(job_id.to_string())).await;
+        }
+    }
+
+    // Staging folders left behind by jobs of the previous run. Only folders
+    // named after a job are touched.
+    async fn clear_staging(config: &Config) {
+        let Ok(mut entries) = tokio::fs::read_dir(&config.storage.staging_dir).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if Uuid::parse_str(&entry.file_name().to_string_lossy()).is_ok() {
+                Self::remove_dir(&entry.path()).await;
+            }
+        }
+    }
+
+    async fn remove_dir(dir: &Path) {
+        match tokio::fs::remove_dir_all(dir).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("failed to remove {}: {}", dir.display(), e),
+        }
+    }
+
+    async fn process(
+        job: &Job,
+        state: &AppState,
+        cancelled: &mut watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        let config = &state.config;
+        let pool = &state.pool;
+        // Everything is written to the staging folder and only moved into the
+        // media folders once the track is stored
+        let storage_key = job.job_id.to_string();
+        let staging = config.storage.staging_dir.join(&storage_key);
+        Self::remove_dir(&staging).await;
+        tokio::fs::create_dir_all(&staging)
+            .await
+            .context("failed to create staging folder")?;
+        let mp3_path = format!("{}.mp3", storage_key);
+        let mp3_file = staging.join(&mp3_path);
+
+        // get information from youtube, to check the clip before downloading
+        let tools = &config.tools;
+        let info = downloader::timeout(
+            Duration::from_secs(tools.timeout_secs),
+            "yt-dlp metadata lookup",
+            state.downloader.info(&job.youtube_url),
+        );
+        let info = Self::cancellable(cancelled, info).await?;
+        let clip = job.clip();
+        let source_secs = progress::parse_clock(&info.duration);
+        clip.validate(source_secs).map_err(|e| anyhow!(e))?;
+        let duration = match source_secs {
+            Some(secs) if !clip.is_full() => clip::format_duration(clip.length(secs)),
+            _ => info.duration,
+        };
+
+        // Another URL of the same video may have been downloaded already
+        Repository::set_job_video_id(job.job_id, &info.video_id, pool).await?;
+        if let Some(track) = Self::existing_track(job, &info.video_id, pool).await? {
+            let message = format!("already downloaded as track {}", track);
+            Repository::append_job_log(job.job_id, &message, pool).await?;
+            state.events.log(job.job_id, &message);
+            Repository::complete_job(job.job_id, track, pool).await?;
+            Self::remove_dir(&staging).await;
+            return Ok(());
+        }
+
+        // Step 1: download
+        let (output, lines) = mpsc::unbounded_channel();
+        let download = state
+            .downloader
+            .download(&job.youtube_url, &mp3_file, clip, output);
+        let limit = Duration::from_secs(tools.download_timeout_secs);
+        let download = downloader::timeout(limit, "download", download);
+        let stage = StageProgress::Download;
+        Self::run_stage(download, lines, stage, job.job_id, state, cancelled).await?;
+
+        // Step 2: HLS
+        let downloaded = (DOWNLOAD_WEIGHT * 100.0) as u8;
+        Repository::update_job_status(job.job_id, JobStatus::Converting, downloaded, pool).await?;
+        state.events.task_changed(job.job_id, pool).await;
+        let hls_playlist = format!("{}/{}", storage_key, PLAYLIST);
+        let dir = staging.join("hls");
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .context("failed to create hls track folder")?;
+
+        let (output, lines) = mpsc::unbounded_channel();
+        let convert = state.transcoder.to_hls(&mp3_file, &dir, output);
+        let convert = downloader::timeout(limit, "conversion", convert);
+        let stage = StageProgress::Convert {
+            duration: progress::parse_clock(&duration),
+        };
+        let segment_format =
+            Self::run_stage(convert, lines, stage, job.job_id, state, cancelled).await?;
+
+        println!("save to db {} with duration {}", info.title, duration);
+        let new_track = Track {
+            title: info.title,
+            duration,
+            mp3_path,
+            hls_playlist,
+            track_id: None,
+            created_at: Utc::now(),
+            source_url: Some(job.youtube_url.clone()),
+            video_id: Some(info.video_id.clone()),
+            clip_start: job.clip_start,
+            clip_end: job.clip_end,
+            segment_format,
+        };
+        let Some(track_id) = Repository::insert_track(&new_track, pool).await? else {
+            // A job for the same video finished first; keep its files
+            let track = Self::existing_track(job, &info.video_id, pool)
+                .await?
+                .ok_or_else(|| anyhow!("track of {} disappeared", info.video_id))?;
+            Self::remove_dir(&staging).await;
+            Repository::complete_job(job.job_id, track, pool).await?;
+            return Ok(());
+        };
+        println!("inserted {}", track_id);
+
+        let published = async {
+            Self::publish(&staging, &new_track.mp3_path, &storage_key, config).await?;
+            match Repository::complete_job(job.job_id, track_id, pool).await? {
+                true => Ok(()),
+                false => Err(Cancelled.into()),
+            }
+        };
+        if let Err(e) = published.await {
+            // Take the track back out so nothing points at missing files
+            if let Err(e) = Repository::delete_track(track_id, pool).await {
+                eprintln!("failed to remove track {}: {:?}", track_id, e);
+            }
+            return Err(e);
+        }
+        Self::remove_dir(&staging).await;
+        Ok(())
+    }
+
+    // Moves the MP3 and the HLS folder of a job from staging into the media
+    // folders. Renames within one filesystem, so readers never see a partial
+    // file.
+    async fn publish(
+        staging: &Path,
+        mp3_path: &str,
+        storage_key: &str,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        let storage = &config.storage;
+        tokio::fs::rename(staging.join(mp3_path), storage.mp3_dir.join(mp3_path))
+            .await
+            .context("failed to move mp3 into place")?;
+        tokio::fs::rename(staging.join("hls"), storage.hls_dir.join(storage_key))
+            .await
+            .context("failed to move hls folder into place")?;
+        Ok(())
+    }
+
+    async fn existing_track(
+        job: &Job,
+        video_id: &str,
+        pool: &Pool<Postgres>,
+    ) -> anyhow::Result<Option<i32>> {
+        let track =
+            Repository::fetch_track_by_source(&job.youtube_url, Some(video_id), job.clip(), pool)
+                .await?;
+        Ok(track.and_then(|track| track.track_id))
+    }
+
+    // Runs one pipeline stage, recording the progress lines its tool prints as
+    // job progress and appending everything else to the job log.
+    async fn run_stage<T>(
+        work: impl Future<Output = anyhow::Result<T>>,
+        mut lines: mpsc::UnboundedReceiver<String>,
+        mut stage: StageProgress,
+        job_id: Uuid,
+        state: &AppState,
+        cancelled: &mut watch::Receiver<bool>,
+    ) -> anyhow::Result<T> {
+        let run = async {
+            tokio::pin!(work);
+            let mut last_progress = None;
+            loop {
+                tokio::select! {
+                    Some(line) = lines.recv() => {
+                        Self::record_line(&line, &mut stage, &mut last_progress, job_id, state).await?;
+                    }
+                    result = &mut work => {
+                        // Lines sent just before the tool finished
+                        while let Ok(line) = lines.try_recv() {
+                            Self::record_line(&line, &mut stage, &mut last_progress, job_id, state)
+                                .await?;
+                        }
+                        return result;
+                    }
+                }
+            }
+        };
+        Self::cancellable(cancelled, run).await
+    }
+
+    async fn record_line(
+        line: &str,
+        stage: &mut StageProgress,
+        last_progress: &mut Option<ProgressUpdate>,
+        job_id: Uuid,
+        state: &AppState,
+    ) -> anyhow::Result<()> {
+        let pool = &state.pool;
+        match stage.parse(line) {
+            Some(update) => {
+                if progress::changed(last_progress.as_ref(), &update) {
+                    Repository::update_job_progress(job_id, &update, pool).await?;
+                    state.events.task_changed(job_id, pool).await;
+                    *last_progress = Some(update);
+                }
+            }
+            None => {
+                Repository::append_job_log(job_id, line, pool).await?;
+                state.events.log(job_id, line);
+            }
+        }
+        Ok(())
+    }
+
+    // Resolves with `Cancelled` as soon as the job is cancelled, dropping `work`.
+    async fn cancellable<T>(
+        cancelled: &mut watch::Receiver<bool>,
+        work: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        tokio::select! {
+            result = work => result,
+            Ok(_) = cancelled.wait_for(|cancelled| *cancelled) => Err(Cancelled.into()),
+        }
+    }
+}
@@ -0,0 +1,627 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use spotify_streaming::clip::Clip;
+use spotify_streaming::config::{Config, DatabaseConfig, SegmentFormat};
+use spotify_streaming::db::Database;
+use spotify_streaming::downloader::{Downloader, FakeDownloader, ToolFuture, TrackInfo};
+use spotify_streaming::model::YtSearchResult;
+use spotify_streaming::process::ToolOutput;
+use spotify_streaming::server::Server;
+use spotify_streaming::transcoder::FakeTranscoder;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{Pool, Postgres};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+// Offline download→HLS→database runs through the HTTP API, with the fake
+// tools. Each test gets its own schema in the database named by
+// `VVINAMP_TEST_DATABASE_URL` (the default database when unset) and its own
+// media folders; tests are skipped when no database is reachable.
+
+const TEST_DATABASE_ENV: &str = "VVINAMP_TEST_DATABASE_URL";
+
+struct TestServer {
+    addr: String,
+    dir: PathBuf,
+    config: Config,
+    admin: Pool<Postgres>,
+    schema: String,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl TestServer {
+    async fn start(
+        downloader: Arc<dyn Downloader>,
+        configure: impl FnOnce(&mut Config),
+    ) -> Option<Self> {
+        let url = std::env::var(TEST_DATABASE_ENV).unwrap_or(DatabaseConfig::default().url);
+        let admin = match PgPoolOptions::new()
+            .max_connections(1)
+            .acquire_timeout(Duration::from_secs(3))
+            .connect(&url)
+            .await
+        {
+            Ok(pool) => pool,
+            Err(e) => {
+                eprintln!("skipping, no test database at {}: {}", url, e);
+                return None;
+            }
+        };
+        let schema = format!("test_{}", uuid::Uuid::new_v4().simple());
+        sqlx::query(&format!("CREATE SCHEMA {}", schema))
+            .execute(&admin)
+            .await
+            .unwrap();
+        let options = PgConnectOptions::from_str(&url)
+            .unwrap()
+            .options([("search_path", schema.as_str())]);
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await
+            .unwrap();
+        Database::migrate_up(&pool).await.unwrap();
+
+        let dir = std::env::temp_dir().join(&schema);
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = Config::default();
+        config.server.bind = format!("127.0.0.1:{}", port);
+        config.storage.mp3_dir = dir.join("mp3");
+        config.storage.hls_dir = dir.join("hls");
+        config.storage.staging_dir = dir.join("staging");
+        for folder in [
+            &config.storage.mp3_dir,
+            &config.storage.hls_dir,
+            &config.storage.staging_dir,
+        ] {
+            tokio::fs::create_dir_all(folder).await.unwrap();
+        }
+        configure(&mut config);
+
+        let transcoder = Arc::new(FakeTranscoder::new(&config.encoding));
+        let server = Server::with_tools(config.clone(), pool, downloader, transcoder);
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move { server.start(shutdown_rx).await });
+
+        let addr = config.server.bind.clone();
+        for _ in 0..50 {
+            if TcpStream::connect(&addr).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Some(Self {
+            addr,
+            dir,
+            config,
+            admin,
+            schema,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    // Sends one request on a fresh connection and reads the whole response.
+    async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<Value>,
+    ) -> TestResponse {
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            body.len()
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        self.send([head, body].concat().as_bytes()).await
+    }
+
+    // Writes `request` as is on a fresh connection and reads the whole response.
+    async fn send(&self, request: &[u8]) -> TestResponse {
+        let mut socket = TcpStream::connect(&self.addr).await.unwrap();
+        socket.write_all(request).await.unwrap();
+        let mut raw = Vec::new();
+        socket.read_to_end(&mut raw).await.unwrap();
+
+        let end = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&raw[..end]).to_string();
+        let mut lines = head.lines();
+        let status = lines.next().unwrap().split(' ').nth(1).unwrap();
+        TestResponse {
+            status: status.parse().unwrap(),
+            headers: lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string()))
+                .collect(),
+            body: raw[end + 4..].to_vec(),
+        }
+    }
+
+    async fn get(&self, path: &str) -> TestResponse {
+        self.request("GET", path, &[], None).await
+    }
+
+    async fn get_with(&self, path: &str, headers: &[(&str, &str)]) -> TestResponse {
+        self.request("GET", path, headers, None).await
+    }
+
+    // Polls a task until it leaves the queue and the workers.
+    async fn wait_for_task(&self, task_id: &str) -> Value {
+        for _ in 0..100 {
+            let task = self.get(&format!("/tasks/{}", task_id)).await.json();
+            if ["done", "failed", "cancelled"].contains(&task["status"].as_str().unwrap()) {
+                return task;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("task {} did not finish", task_id);
+    }
+
+    async fn download(&self, url: &str) -> Value {
+        let body = json!({ "youtube_url": url, "title": "ignored" });
+        let response = self.request("POST", "/download", &[], Some(body)).await;
+        assert_eq!(response.status, 200, "{}", response.text());
+        response.json()
+    }
+
+    // Downloads `url` and waits until it is stored as a track.
+    async fn stored_track(&self, url: &str) -> i64 {
+        let task_id = self.download(url).await["task_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let task = self.wait_for_task(&task_id).await;
+        assert_eq!(task["status"], "done", "{}", task);
+        task["track_id"].as_i64().unwrap()
+    }
+
+    async fn segment_format(&self, track_id: i64) -> String {
+        let (format,): (String,) = sqlx::query_as(&format!(
+            "SELECT segment_format FROM {}.tracks WHERE track_id = $1",
+            self.schema
+        ))
+        .bind(track_id as i32)
+        .fetch_one(&self.admin)
+        .await
+        .unwrap();
+        format
+    }
+
+    async fn track_count(&self) -> i64 {
+        let (count,): (i64,) =
+            sqlx::query_as(&format!("SELECT count(*) FROM {}.tracks", self.schema))
+                .fetch_one(&self.admin)
+                .await
+                .unwrap();
+        count
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+        // The pool is gone with the runtime; drop the schema on a connection of its own
+        let admin = self.admin.clone();
+        let schema = self.schema.clone();
+        let _ = std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let _ = sqlx::query(&format!("DROP SCHEMA {} CASCADE", schema))
+                        .execute(&admin)
+                        .await;
+                })
+        })
+        .join();
+    }
+}
+
+struct TestResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl TestResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+// Fake downloader whose downloads fail once the file has been written.
+struct FailingDownloader(FakeDownloader);
+
+impl Downloader for FailingDownloader {
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+        dest: &'a Path,
+        clip: Clip,
+        output: ToolOutput,
+    ) -> ToolFuture<'a, ()> {
+        Box::pin(async move {
+            self.0.download(url, dest, clip, output).await?;
+            anyhow::bail!("ERROR: unable to extract audio")
+        })
+    }
+
+    fn info<'a>(&'a self, url: &'a str) -> ToolFuture<'a, TrackInfo> {
+        self.0.info(url)
+    }
+
+    fn video_id(&self, url: &str) -> Option<String> {
+        self.0.video_id(url)
+    }
+
+    fn search<'a>(&'a self, query: &'a str) -> ToolFuture<'a, Vec<YtSearchResult>> {
+        self.0.search(query)
+    }
+}
+
+async fn with_server<F, Fut>(downloader: Arc<dyn Downloader>, test: F)
+where
+    F: FnOnce(TestServer) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    with_config(downloader, |_| {}, test).await
+}
+
+async fn with_config<F, Fut>(
+    downloader: Arc<dyn Downloader>,
+    configure: impl FnOnce(&mut Config),
+    test: F,
+) where
+    F: FnOnce(TestServer) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    if let Some(server) = TestServer::start(downloader, configure).await {
+        test(server).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn downloads_are_converted_stored_and_served() {
+    with_server(Arc::new(FakeDownloader::default()), |server| async move {
+        let task_id = server.download("https://example.com/watch/first").await["task_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let task = server.wait_for_task(&task_id).await;
+        assert_eq!(task["status"], "done", "{}", task);
+        assert_eq!(task["progress"], 100);
+        let track_id = task["track_id"].as_i64().unwrap();
+
+        let track = server.get(&format!("/tracks/{}", track_id)).await;
+        assert_eq!(track.status, 200);
+        assert_eq!(track.json()["title"], "Fake track first");
+
+        // Master playlist, one variant per bitrate
+        let master = server
+            .get(&format!("/tracks/{}/playlist.m3u8", track_id))
+            .await;
+        assert_eq!(master.status, 200);
+        assert_eq!(
+            master.header("content-type"),
+            Some("application/vnd.apple.mpegurl")
+        );
+        let variants: Vec<String> = master
+            .text()
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+        assert_eq!(variants.len(), server.config.encoding.bitrates.len());
+
+        let media = server.get(&variants[0]).await;
+        assert_eq!(media.status, 200);
+        let media = media.text();
+        assert!(media.contains("#EXT-X-ENDLIST"), "{}", media);
+        let segments: Vec<&str> = media.lines().filter(|l| !l.starts_with('#')).collect();
+        // 30s of whole frames runs just past the third 10s segment
+        assert_eq!(segments.len(), 4);
+
+        let segment = server.get(segments[0]).await;
+        assert_eq!(segment.status, 200);
+        assert_eq!(segment.header("content-type"), Some("video/mp2t"));
+        assert!(!segment.body.is_empty());
+        assert_eq!(server.segment_format(track_id).await, "ts");
+
+        // DASH players cannot play MPEG-TS
+        let mpd = format!("/tracks/{}/manifest.mpd", track_id);
+        let mpd = server.get(&mpd).await;
+        assert_eq!(mpd.status, 409);
+        assert_eq!(mpd.json()["code"], "conflict");
+
+        let stream = server.get(&format!("/tracks/{}/stream", track_id)).await;
+        assert_eq!(stream.status, 200);
+        assert_eq!(stream.header("content-type"), Some("audio/mpeg"));
+        assert_eq!(stream.body, FakeDownloader::silence(30.0));
+
+        // The files were moved out of staging
+        let staging = server.config.storage.staging_dir.join(&task_id);
+        assert!(!staging.exists());
+        assert!(server.config.storage.hls_dir.join(&task_id).is_dir());
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tracks_record_their_segment_format() {
+    let fmp4 = |config: &mut Config| config.encoding.segment_format = SegmentFormat::Fmp4;
+    with_config(
+        Arc::new(FakeDownloader::default()),
+        fmp4,
+        |server| async move {
+            let track_id = server.stored_track("https://example.com/watch/fmp4").await;
+            assert_eq!(server.segment_format(track_id).await, "fmp4");
+
+            let variant = format!("/tracks/{}/variants/64k.m3u8", track_id);
+            let media = server.get(&variant).await.text();
+            assert!(media.contains("#EXT-X-MAP:URI="), "{}", media);
+            assert!(media.contains("#EXT-X-VERSION:6"), "{}", media);
+            let segments: Vec<&str> = media.lines().filter(|l| !l.starts_with('#')).collect();
+            let segment = server.get(segments[0]).await;
+            assert_eq!(segment.status, 200);
+            assert_eq!(segment.header("content-type"), Some("audio/mp4"));
+            let init = server
+                .get(&format!("/tracks/{}/segments/64k_init.mp4", track_id))
+                .await;
+            assert_eq!(init.status, 200);
+
+            // Files of the other container are not part of the track
+            let ts = format!("/tracks/{}/segments/64k_segment_000.ts", track_id);
+            assert_eq!(server.get(&ts).await.status, 404);
+
+            let mpd_path = format!("/tracks/{}/manifest.mpd", track_id);
+            let mpd = server.get(&mpd_path).await;
+            assert_eq!(mpd.status, 200);
+            assert_eq!(mpd.header("content-type"), Some("application/dash+xml"));
+            let etag = mpd.header("etag").unwrap().to_string();
+            let cached = server
+                .get_with(&mpd_path, &[("If-None-Match", &etag)])
+                .await;
+            assert_eq!(cached.status, 304);
+            let master = server
+                .get(&format!("/tracks/{}/playlist.m3u8", track_id))
+                .await;
+            assert_ne!(master.header("etag"), Some(etag.as_str()));
+            let mpd = mpd.text();
+            assert!(mpd.contains("mimeType=\"audio/mp4\""), "{}", mpd);
+            assert!(!mpd.contains("mp2t"), "{}", mpd);
+            let init = format!("sourceURL=\"/tracks/{}/segments/64k_init.mp4\"", track_id);
+            assert!(mpd.contains(&init), "{}", mpd);
+            let representations = mpd.matches("<Representation ").count();
+            assert_eq!(representations, server.config.encoding.bitrates.len());
+
+            // A changed media playlist changes the manifest
+            let mut track_dirs = std::fs::read_dir(&server.config.storage.hls_dir).unwrap();
+            let variant = track_dirs.next().unwrap().unwrap().path().join("64k.m3u8");
+            let mut playlist = std::fs::read_to_string(&variant).unwrap();
+            playlist.push('\n');
+            std::fs::write(&variant, playlist).unwrap();
+            let changed = server
+                .get_with(&mpd_path, &[("If-None-Match", &etag)])
+                .await;
+            assert_eq!(changed.status, 200);
+            assert_ne!(changed.header("etag"), Some(etag.as_str()));
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stored_sources_are_not_downloaded_again() {
+    with_server(Arc::new(FakeDownloader::default()), |server| async move {
+        let first = server.download("https://example.com/watch/again").await;
+        let task_id = first["task_id"].as_str().unwrap();
+        // Still queued or running: the same task is handed out
+        let in_flight = server.download("https://example.com/watch/again").await;
+        assert_eq!(in_flight["task_id"], task_id);
+        assert_eq!(in_flight["existing"], true);
+
+        let task = server.wait_for_task(task_id).await;
+        let stored = server.download("https://example.com/watch/again").await;
+        assert_eq!(stored["track_id"], task["track_id"]);
+        assert_eq!(stored["existing"], true);
+        assert_eq!(server.track_count().await, 1);
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_requests_queue_one_job() {
+    with_server(Arc::new(FakeDownloader::default()), |server| async move {
+        let url = "https://example.com/watch/concurrent";
+        let responses = tokio::join!(
+            server.download(url),
+            server.download(url),
+            server.download(url),
+            server.download(url),
+        );
+        let responses = [responses.0, responses.1, responses.2, responses.3];
+        let queued: Vec<&Value> = responses
+            .iter()
+            .filter(|response| response["existing"].is_null())
+            .collect();
+        assert_eq!(queued.len(), 1, "{:?}", responses);
+
+        let task_id = queued[0]["task_id"].as_str().unwrap();
+        server.wait_for_task(task_id).await;
+        let (jobs,): (i64,) =
+            sqlx::query_as(&format!("SELECT count(*) FROM {}.jobs", server.schema))
+                .fetch_one(&server.admin)
+                .await
+                .unwrap();
+        assert_eq!(jobs, 1);
+        assert_eq!(server.track_count().await, 1);
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_downloads_leave_no_track_or_files() {
+    let downloader = Arc::new(FailingDownloader(FakeDownloader::default()));
+    with_server(downloader, |server| async move {
+        let task_id = server.download("https://example.com/watch/broken").await["task_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let task = server.wait_for_task(&task_id).await;
+        assert_eq!(task["status"], "failed");
+        assert!(
+            task["error"]
+                .as_str()
+                .unwrap()
+                .contains("unable to extract audio"),
+            "{}",
+            task
+        );
+        assert_eq!(server.track_count().await, 0);
+
+        let storage = &server.config.storage;
+        for dir in [&storage.mp3_dir, &storage.hls_dir, &storage.staging_dir] {
+            let mut entries = tokio::fs::read_dir(dir).await.unwrap();
+            assert!(entries.next_entry().await.unwrap().is_none(), "{:?}", dir);
+        }
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unknown_tracks_and_tasks_are_not_found() {
+    with_server(Arc::new(FakeDownloader::default()), |server| async move {
+        assert_eq!(server.get("/tracks/4242/playlist.m3u8").await.status, 404);
+        let task = format!("/tasks/{}", uuid::Uuid::new_v4());
+        assert_eq!(server.get(&task).await.status, 404);
+        let response = server
+            .request("POST", "/download", &[], Some(json!({ "title": "no url" })))
+            .await;
+        assert_eq!(response.status, 400);
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn oversized_chunked_bodies_are_rejected() {
+    with_server(Arc::new(FakeDownloader::default()), |server| async move {
+        // Sizes past the limit, past `usize` and adding up past it
+        for chunks in [
+            "900000\r\n",
+            "ffffffffffffffff\r\n",
+            "fffffffffffffffffffff\r\n",
+            "4\r\nabcd\r\nfffffffffffffffe\r\n",
+        ] {
+            let request = format!(
+                "POST /download HTTP/1.1\r\nHost: test\r\n\
+                 Transfer-Encoding: chunked\r\n\r\n{}",
+                chunks
+            );
+            let response = server.send(request.as_bytes()).await;
+            assert_eq!(response.status, 413, "{:?}", chunks);
+            assert_eq!(response.json()["code"], "payload_too_large");
+        }
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stored_mp3s_are_served_as_hls() {
+    with_server(Arc::new(FakeDownloader::default()), |server| async move {
+        let track_id = server.stored_track("https://example.com/watch/mp3").await;
+        let mp3 = FakeDownloader::silence(30.0);
+
+        let playlist = server
+            .get(&format!("/tracks/{}/mp3.m3u8?packed=false", track_id))
+            .await;
+        assert_eq!(playlist.status, 200);
+        let text = playlist.text();
+        assert!(text.contains("#EXT-X-BYTERANGE:"), "{}", text);
+        let stream = server.get(&format!("/tracks/{}/stream", track_id)).await;
+        assert_ne!(playlist.header("etag"), stream.header("etag"));
+
+        // Packed audio segments by default
+        let packed = server.get(&format!("/tracks/{}/mp3.m3u8", track_id)).await;
+        assert_eq!(packed.status, 200);
+        assert_ne!(packed.header("etag"), playlist.header("etag"));
+        let packed = packed.text();
+        let uris: Vec<&str> = packed.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(uris.len(), 3);
+        // Each segment starts with its ID3 timestamp tag, and the audio
+        // behind the tags covers the whole file, one after the other
+        const OWNER: &[u8] = b"com.apple.streaming.transportStreamTimestamp\0";
+        let mut body: Vec<u8> = Vec::new();
+        let mut timestamps = Vec::new();
+        for uri in &uris {
+            let segment = server.get(uri).await;
+            assert_eq!(segment.status, 200);
+            assert_eq!(segment.header("content-type"), Some("audio/mpeg"));
+            assert_eq!(&segment.body[..3], b"ID3");
+            assert_eq!(&segment.body[10..14], b"PRIV");
+            assert_eq!(&segment.body[20..20 + OWNER.len()], OWNER);
+            let timestamp = &segment.body[20 + OWNER.len()..73];
+            timestamps.push(u64::from_be_bytes(timestamp.try_into().unwrap()));
+            body.extend(&segment.body[73..]);
+        }
+        assert_eq!(body, mp3);
+        assert_eq!(timestamps[0], 0);
+        assert!(timestamps[1].abs_diff(900_000) < 9_000, "{:?}", timestamps);
+        assert!(timestamps[2] > timestamps[1]);
+
+        let segment = server.get(uris[1]).await;
+        let etag = segment.header("etag").unwrap();
+        assert!(segment.header("last-modified").is_some());
+        assert_ne!(Some(etag), server.get(uris[0]).await.header("etag"));
+        let cached = server.get_with(uris[1], &[("If-None-Match", etag)]).await;
+        assert_eq!(cached.status, 304);
+        assert!(cached.body.is_empty());
+
+        let partial = server.get_with(uris[1], &[("Range", "bytes=4-9")]).await;
+        assert_eq!(partial.status, 206);
+        assert_eq!(partial.body, segment.body[4..10]);
+        let content_range = format!("bytes 4-9/{}", segment.body.len());
+        assert_eq!(
+            partial.header("content-range"),
+            Some(content_range.as_str())
+        );
+        let stale = server
+            .get_with(
+                uris[1],
+                &[("Range", "bytes=4-9"), ("If-Range", "\"stale\"")],
+            )
+            .await;
+        assert_eq!(stale.status, 200);
+        assert_eq!(stale.body, segment.body);
+    })
+    .await;
+}